@@ -0,0 +1,9 @@
+#include game::light_shadows
+
+uniform vec4 light_position_range;
+
+varying vec3 ws_position;
+
+void main() {
+    gl_FragColor = pack_light_depth(distance(ws_position, light_position_range.xyz) / light_position_range.w);
+}
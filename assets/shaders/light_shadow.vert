@@ -0,0 +1,12 @@
+attribute vec3 Vertex_Position;
+
+uniform mat4 world_from_local;
+uniform mat4 light_clip_from_world;
+
+varying vec3 ws_position;
+
+void main() {
+    vec4 position = world_from_local * vec4(Vertex_Position, 1.0);
+    ws_position = position.xyz;
+    gl_Position = light_clip_from_world * position;
+}
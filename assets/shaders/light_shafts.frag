@@ -0,0 +1,78 @@
+#include std::math
+#include game::fog_noise
+#include game::light_shadows
+
+// Light from the spot light cones scattered towards the view by the `Fog`, see light_shafts.rs.
+// Ray marched from the view up to the view distance prepass, which is left at 1.0 where nothing
+// was drawn so the shafts also show against the sky.
+
+varying vec2 screen_uv;
+
+uniform sampler2D view_distance;
+uniform mat4 world_from_clip;
+
+// Henyey-Greenstein, forward scattering so beams pointing towards the view are brighter.
+float shaft_phase(float cos_theta) {
+    float g = 0.5;
+    float denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+
+void main() {
+    float blender_exposure = 0.2; // Same as std_mat.frag
+    float max_distance = ub_light_shaft_max_distance;
+    float frag_dist = min(unpack_light_depth(texture2D(view_distance, screen_uv)), 1.0) * max_distance;
+    vec4 near = world_from_clip * vec4(screen_uv * 2.0 - 1.0, -1.0, 1.0);
+    vec3 ray_dir = normalize(near.xyz / near.w - ub_view_position);
+
+    // Interleaved gradient noise, offset every frame.
+    vec2 pixel = gl_FragCoord.xy + 5.588238 * mod(ub_frame, 64.0);
+    float seed = fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+
+    float step_len = frag_dist / float(LIGHT_SHAFT_STEPS);
+    float density = ub_fog_color.w;
+    vec3 color = vec3(0.0);
+    for (int s = 0; s < LIGHT_SHAFT_STEPS; s++) {
+        float t = (float(s) + seed) * step_len;
+        vec3 p = ub_view_position + ray_dir * t;
+        vec3 step_color = vec3(0.0);
+        for (int i = 0; i < MAX_SHAFT_LIGHTS; i++) {
+            if (i < ub_light_shaft_count) {
+                vec4 position_range = ub_light_shaft_position_range[i];
+                vec3 to_light = position_range.xyz - p;
+                float dist_sq = dot(to_light, to_light);
+                vec4 dos = ub_light_shaft_dir_offset_scale[i];
+                vec3 spot_dir = octahedral_decode(dos.xy);
+                vec3 L = to_light * inversesqrt(max(dist_sq, 1e-4));
+                float spot = saturate(dot(spot_dir, -L) * dos.w + dos.z);
+                float range_factor = dist_sq / (position_range.w * position_range.w);
+                float range_falloff = saturate(1.0 - range_factor * range_factor);
+                float attenuation = spot * spot * range_falloff * range_falloff / max(dist_sq, 1e-4);
+                if (attenuation > 0.0) {
+                    #ifdef LIGHT_SHADOWS
+                    vec4 shadow = ub_light_shaft_shadow[i];
+                    if (shadow.x >= 0.0) {
+                        attenuation *= sample_light_shadow(ub_light_shadow_atlas, ub_light_shadow_tiles_per_row, shadow,
+                                position_range.w, spot_dir, -to_light);
+                    }
+                    #endif // LIGHT_SHADOWS
+                    step_color += ub_light_shaft_color_radius[i].rgb * attenuation * shaft_phase(dot(L, ray_dir));
+                }
+            }
+        }
+        float noise = fog_density_noise_at(p, ub_fog_noise, ub_fog_noise_offset);
+        // Same linear extinction std_mat fogs surfaces with, min(distance * density, 1.0).
+        float transmittance = 1.0 - min(t * density * noise, 1.0);
+        color += step_color * noise * transmittance;
+    }
+    color *= density * step_len * ub_light_shaft_intensity * ub_view_exposure * blender_exposure;
+
+    // Alpha 0 leaves the target's alpha alone, the hdr tonemap reads it.
+    #ifdef ADDITIVE
+    gl_FragColor = vec4(color, 0.0);
+    #else
+    // Screen blended onto a target that's already tonemapped (or hdr encoded), this keeps it
+    // from clipping.
+    gl_FragColor = vec4(1.0 - exp(-color), 0.0);
+    #endif // ADDITIVE
+}
@@ -1,10 +1,117 @@
+#ifdef TONEMAP
+#include std::math
+#include std::agx
+#include game::hdr
+#endif // TONEMAP
+
+// A pass is at most one of CHROMATIC_ABERRATION and BLUR, or TONEMAP, followed by any of the
+// effects that only read the current pixel. See `PostPass`.
+
 varying vec2 screen_uv;
 
 uniform sampler2D render_target;
+#ifdef TONEMAP
+uniform float exposure;
+#endif
+#ifdef CHROMATIC_ABERRATION
+uniform vec4 aberration_params;
+#endif
+#ifdef BLUR
+uniform vec4 blur_params;
+uniform vec2 texel_size;
+#endif
+#ifdef VIGNETTE
+uniform vec4 vignette_params;
+#endif
+#ifdef FILM_GRAIN
+uniform vec4 grain_params;
+uniform float time;
+#endif
+#ifdef COLOR_TINT
+uniform vec4 tint_params;
+uniform vec4 tint_color;
+#endif
+#ifdef SCANLINES
+uniform vec4 scanline_params;
+#endif
+
+#ifdef TONEMAP
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+vec3 aces_fitted(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 linear_to_srgb(vec3 color) {
+    vec3 lo = color * 12.92;
+    vec3 hi = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return mix(lo, hi, step(vec3(0.0031308), color));
+}
+#endif // TONEMAP
 
 void main() {
-    gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
-    gl_FragColor.rgb += texture2D(render_target, screen_uv + 0.005).rgb * vec3(0.0, 1.0, 1.0) * 0.25;
-    gl_FragColor.rgb += texture2D(render_target, screen_uv).rgb * 0.5;
-    gl_FragColor.rgb += texture2D(render_target, screen_uv - 0.005).rgb * vec3(0.0, 1.0, 0.0) * 0.25;
+#ifdef CHROMATIC_ABERRATION
+    float offset = aberration_params.x;
+    vec3 color = texture2D(render_target, screen_uv + offset).rgb * vec3(0.0, 1.0, 1.0) * 0.25;
+    color += texture2D(render_target, screen_uv).rgb * 0.5;
+    color += texture2D(render_target, screen_uv - offset).rgb * vec3(0.0, 1.0, 0.0) * 0.25;
+#else
+#ifdef BLUR
+    vec2 o = texel_size * blur_params.x;
+    vec3 color = 4.0 * texture2D(render_target, screen_uv).rgb;
+    color += 2.0 * texture2D(render_target, screen_uv + vec2(o.x, 0.0)).rgb;
+    color += 2.0 * texture2D(render_target, screen_uv - vec2(o.x, 0.0)).rgb;
+    color += 2.0 * texture2D(render_target, screen_uv + vec2(0.0, o.y)).rgb;
+    color += 2.0 * texture2D(render_target, screen_uv - vec2(0.0, o.y)).rgb;
+    color += texture2D(render_target, screen_uv + o).rgb;
+    color += texture2D(render_target, screen_uv - o).rgb;
+    color += texture2D(render_target, screen_uv + vec2(o.x, -o.y)).rgb;
+    color += texture2D(render_target, screen_uv + vec2(-o.x, o.y)).rgb;
+    color /= 16.0;
+#else
+    vec4 texel = texture2D(render_target, screen_uv);
+    vec3 color = texel.rgb;
+#endif // BLUR
+#endif // CHROMATIC_ABERRATION
+
+#ifdef TONEMAP
+    // std_mat leaves alpha 0 where it drew nothing, that's the ClearColor and already srgb.
+    if (texel.a > 0.0) {
+#ifndef HDR_FLOAT_TARGET
+        color = hdr_decode(color);
+#endif // HDR_FLOAT_TARGET
+        color *= exposure;
+#ifdef TONEMAP_ACES
+        color = linear_to_srgb(aces_fitted(color));
+#endif
+#ifdef TONEMAP_REINHARD
+        color = linear_to_srgb(color / (1.0 + color));
+#endif
+#ifdef TONEMAP_AGX
+        color = agx_tonemapping(color); // in: linear, out: srgb
+#endif
+    }
+#endif // TONEMAP
+
+#ifdef VIGNETTE
+    // 0 at the center, 1 in the corners.
+    float d = length(screen_uv - 0.5) * 1.41421356;
+    color *= 1.0 - vignette_params.x * smoothstep(vignette_params.y, 1.0, d);
+#endif
+
+#ifdef FILM_GRAIN
+    vec2 p = gl_FragCoord.xy + fract(time * vec2(12.9898, 78.233)) * 1000.0;
+    float noise = fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453) - 0.5;
+    color += noise * grain_params.x;
+#endif
+
+#ifdef COLOR_TINT
+    color = mix(color, color * tint_color.rgb, tint_params.x);
+#endif
+
+#ifdef SCANLINES
+    float line = 0.5 + 0.5 * sin(screen_uv.y * scanline_params.x * 6.2831853);
+    color *= 1.0 - scanline_params.y * (1.0 - line);
+#endif
+
+    gl_FragColor = vec4(color, 1.0);
 }
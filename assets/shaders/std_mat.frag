@@ -9,6 +9,16 @@
 #include game::caustics
 #endif //THERES_CAUSTICS
 
+#ifdef LIGHT_SHADOWS
+#include game::light_shadows
+#endif // LIGHT_SHADOWS
+
+#ifdef HDR_OUTPUT
+#include game::hdr
+#endif // HDR_OUTPUT
+
+#include game::fog_noise
+
 vec3 apply_pbr_lighting(vec3 V, vec3 diffuse_color, vec3 F0, vec3 vert_normal, vec3 normal, float perceptual_roughness,
     float environment_occlusion, float diffuse_transmission, vec2 screen_uv, vec2 view_resolution, vec3 ws_position, float dir_shadow) {
     float roughness = perceptual_roughness * perceptual_roughness;
@@ -51,8 +61,16 @@ vec3 apply_pbr_lighting(vec3 V, vec3 diffuse_color, vec3 F0, vec3 vert_normal, v
                 vec4 light_color_radius = ub_point_light_color_radius[i];
                 vec4 dos = ub_spot_light_dir_offset_scale[i];
                 vec3 spot_dir = octahedral_decode(dos.xy);
-                output_color += point_light(V, diffuse_color, F0, normal, roughness, diffuse_transmission, to_light,
-                        light_position_range.w, light_color_radius.rgb, spot_dir, dos.z, dos.w);
+                float light_shadow = 1.0;
+                #ifdef LIGHT_SHADOWS
+                vec4 shadow = ub_point_light_shadow[i];
+                if (shadow.x >= 0.0) {
+                    light_shadow = sample_light_shadow(ub_light_shadow_atlas, ub_light_shadow_tiles_per_row, shadow,
+                            light_position_range.w, spot_dir, -to_light);
+                }
+                #endif // LIGHT_SHADOWS
+                output_color += light_shadow * point_light(V, diffuse_color, F0, normal, roughness, diffuse_transmission,
+                        to_light, light_position_range.w, light_color_radius.rgb, spot_dir, dos.z, dos.w);
             }
         }
     }
@@ -72,6 +90,10 @@ uniform sampler2D reflect_texture;
 uniform bool read_reflection;
 uniform vec3 reflection_plane_position;
 uniform vec3 reflection_plane_normal;
+uniform float cascade_blend;
+#ifdef CASCADE_DEBUG
+uniform vec3 cascade_debug_color;
+#endif // CASCADE_DEBUG
 
 int sampleTrilinearCorner(vec3 f, float u) {
     float fx = f.x, fy = f.y, fz = f.z;
@@ -161,7 +183,11 @@ vec3 corner_offset(int i) {
 }
 
 vec4 sample_cascade_stochastic(vec3 ws_position, vec3 ws_normal, vec2 screen_uv, vec3 diffuse_color) {
-    vec3 ls_position = (ws_position - ub_cascade_position) / ub_cascade_spacing;
+    if (ub_fallback_ambient.w > 0.5) {
+        return vec4(ub_fallback_ambient.rgb * diffuse_color, 1.0);
+    }
+    vec3 cs_position = (ub_cascade_from_world * vec4(ws_position, 1.0)).xyz;
+    vec3 ls_position = (cs_position - ub_cascade_position) / ub_cascade_spacing;
     vec3 base = floor(ls_position);
     base = min(max(base, vec3(0.0)), ub_cascade_res - 2.0);
     vec3 alpha = saturate(ls_position - base);
@@ -184,8 +210,40 @@ vec4 sample_cascade_stochastic(vec3 ws_position, vec3 ws_normal, vec2 screen_uv,
     return vec4(color, probe_id_info.z);
 }
 
+vec4 sample_blend_cascade_stochastic(vec3 ws_position, vec3 ws_normal, vec2 screen_uv, vec3 diffuse_color) {
+    if (ubb_fallback_ambient.w > 0.5) {
+        return vec4(ubb_fallback_ambient.rgb * diffuse_color, 1.0);
+    }
+    vec3 cs_position = (ubb_cascade_from_world * vec4(ws_position, 1.0)).xyz;
+    vec3 ls_position = (cs_position - ubb_cascade_position) / ubb_cascade_spacing;
+    vec3 base = floor(ls_position);
+    base = min(max(base, vec3(0.0)), ubb_cascade_res - 2.0);
+    vec3 alpha = saturate(ls_position - base);
+
+    vec2 id_texel_half = ubb_id_texel * 0.5;
+
+    int i = sampleTrilinearCorner(alpha, hash(screen_uv + hash(ub_frame)));
+    vec3 offset = corner_offset(i);
+    vec3 probe_pos = base + offset;
+    vec2 ls_id_position = vec2(probe_pos.x, probe_pos.y + probe_pos.z * ubb_cascade_res.y);
+    vec3 trilinear = mix(1.0 - alpha, alpha, offset);
+    float probe_pad_size = ubb_probe_size + 2.0;
+    vec2 oct = octEncode(ws_normal) * 0.5 + 0.5;
+    vec4 probe_id_info = texture2D(ubb_probes_id, id_texel_half + ubb_id_texel * ls_id_position);
+    vec2 probe_xy_id = floor(probe_id_info.xy * 255.0 + 0.5);
+    vec2 uv = ubb_gi_texel + oct * ubb_probe_size * ubb_gi_texel + (ubb_gi_texel * probe_pad_size * probe_xy_id);
+    vec3 probe_irradiance = 0.5 * PI * rgbe2rgb(texture2D(ubb_probes_gi, uv));
+    vec3 color = probe_irradiance * diffuse_color * 1000.0 * 5.0;
+
+    return vec4(color, probe_id_info.z);
+}
+
 vec4 sample_view_cascade_stochastic(vec3 ws_position, vec3 ws_normal, vec2 screen_uv, vec3 diffuse_color) {
-    vec3 ls_position = (ws_position - ubv_cascade_position) / ubv_cascade_spacing;
+    if (ubv_fallback_ambient.w > 0.5) {
+        return vec4(ubv_fallback_ambient.rgb * diffuse_color, 1.0);
+    }
+    vec3 cs_position = (ubv_cascade_from_world * vec4(ws_position, 1.0)).xyz;
+    vec3 ls_position = (cs_position - ubv_cascade_position) / ubv_cascade_spacing;
     vec3 base = floor(ls_position);
     base = min(max(base, vec3(0.0)), ubv_cascade_res - 2.0);
     vec3 alpha = saturate(ls_position - base);
@@ -208,11 +266,24 @@ vec4 sample_view_cascade_stochastic(vec3 ws_position, vec3 ws_normal, vec2 scree
     return vec4(color, probe_id_info.z);
 }
 
-vec3 sample_fog(float blend, float seed, vec3 atm_color, vec3 sample_normal, vec2 screen_uv, vec3 V) {
-    vec3 sample_pos = ws_position * (1.0 - seed) + seed * ub_view_position;
+#ifdef THERES_CAUSTICS
+// Shared by surfaces and the fog so their caustics line up.
+float caustics_pattern(vec3 ws_pos) {
+    return water_caustics(vec3(ws_pos.x, 0.0, ws_pos.z) - ub_caustics_offset);
+}
+#endif // THERES_CAUSTICS
+
+// Scales fog density at p, 1.0 without `ub_fog_noise`.
+float fog_density_noise(vec3 p) {
+    return fog_density_noise_at(p, ub_fog_noise, ub_fog_noise_offset);
+}
+
+vec3 sample_fog_at(vec3 sample_pos, float blend, vec3 atm_color, vec3 sample_normal, vec2 screen_uv, vec3 V,
+    vec3 caustics) {
     vec4 col_shad = vec4(0.0);
-    bool inside_view_cascade = all(greaterThan(sample_pos, ubv_cascade_position)) &&
-            all(lessThan(sample_pos, ubv_cascade_position + ubv_cascade_spacing * ubv_cascade_res));
+    vec3 view_cs_pos = (ubv_cascade_from_world * vec4(sample_pos, 1.0)).xyz;
+    bool inside_view_cascade = all(greaterThan(view_cs_pos, ubv_cascade_position)) &&
+            all(lessThan(view_cs_pos, ubv_cascade_position + ubv_cascade_spacing * ubv_cascade_res));
     if (inside_view_cascade) {
         col_shad = sample_view_cascade_stochastic(sample_pos, sample_normal, screen_uv, vec3(blend));
     } else {
@@ -232,11 +303,9 @@ vec3 sample_fog(float blend, float seed, vec3 atm_color, vec3 sample_normal, vec
     //        ub_directional_light_dir, ub_directional_light_color);
 
     #ifdef THERES_CAUSTICS
-    vec3 pos = vec3(sample_pos.x, -0.1 * ub_time, sample_pos.z);
-    vec3 o = vec3(1.0, 0.0, 1.0) * 0.02;
-    float w = water_caustics(pos);
+    float w = caustics_pattern(sample_pos);
     float intensity = exp(w * 4. - 1.) / (sample_pos.y + 1.0);
-    color += atm_color * intensity * 2000.0 * ub_caustics.rgb;
+    color += atm_color * intensity * 2000.0 * caustics;
     #endif
 
     color += apply_pbr_lighting(V, atm_color, vec3(0.0), sample_normal, sample_normal, 1.0,
@@ -245,6 +314,70 @@ vec3 sample_fog(float blend, float seed, vec3 atm_color, vec3 sample_normal, vec
     return color;
 }
 
+vec3 sample_fog(float blend, float seed, vec3 atm_color, vec3 sample_normal, vec2 screen_uv, vec3 V) {
+    vec3 sample_pos = ws_position * (1.0 - seed) + seed * ub_view_position;
+    return sample_fog_at(sample_pos, blend, atm_color, sample_normal, screen_uv, V, ub_caustics.rgb);
+}
+
+#ifdef FOG_VOLUMES
+// Where the view ray is inside volume i, in world distance along the ray. y < x if it misses.
+vec2 fog_volume_span(int i, vec3 ray_origin, vec3 ray_dir) {
+    vec4 rx = ub_fog_volume_local_x[i];
+    vec4 ry = ub_fog_volume_local_y[i];
+    vec4 rz = ub_fog_volume_local_z[i];
+    vec3 o = vec3(dot(rx.xyz, ray_origin) + rx.w, dot(ry.xyz, ray_origin) + ry.w, dot(rz.xyz, ray_origin) + rz.w);
+    vec3 d = vec3(dot(rx.xyz, ray_dir), dot(ry.xyz, ray_dir), dot(rz.xyz, ray_dir));
+    if (ub_fog_volume_params[i].x > 0.5) {
+        float a = dot(d, d);
+        float b = dot(o, d);
+        float disc = b * b - a * (dot(o, o) - 1.0);
+        if (disc < 0.0) {
+            return vec2(1.0, 0.0);
+        }
+        float sq = sqrt(disc);
+        return vec2(-b - sq, -b + sq) / a;
+    }
+    d = mix(d, vec3(1e-6), step(abs(d), vec3(1e-6)));
+    vec3 t0 = (-1.0 - o) / d;
+    vec3 t1 = (1.0 - o) / d;
+    vec3 t_near = min(t0, t1);
+    vec3 t_far = max(t0, t1);
+    return vec2(max(max(t_near.x, t_near.y), t_near.z), min(min(t_far.x, t_far.y), t_far.z));
+}
+
+// 0 on the surface of volume i, 1 at falloff or deeper.
+float fog_volume_edge(int i, vec3 p) {
+    vec4 rx = ub_fog_volume_local_x[i];
+    vec4 ry = ub_fog_volume_local_y[i];
+    vec4 rz = ub_fog_volume_local_z[i];
+    vec3 local = vec3(dot(rx.xyz, p) + rx.w, dot(ry.xyz, p) + ry.w, dot(rz.xyz, p) + rz.w);
+    vec4 params = ub_fog_volume_params[i];
+    float depth = 1.0 - (params.x > 0.5 ? length(local) : max(max(abs(local.x), abs(local.y)), abs(local.z)));
+    return params.y > 0.0 ? saturate(depth / params.y) : 1.0;
+}
+
+// Each volume is lit once at a jittered point inside its span, and blends over what's behind it.
+vec3 apply_fog_volumes(vec3 color, float seed, float frag_dist, vec2 screen_uv, vec3 V) {
+    vec3 ray_dir = -V;
+    for (int i = 0; i < MAX_FOG_VOLUMES; i++) {
+        if (i < ub_fog_volume_count) {
+            vec2 span = fog_volume_span(i, ub_view_position, ray_dir);
+            span = vec2(max(span.x, 0.0), min(span.y, frag_dist));
+            if (span.y > span.x) {
+                vec3 p = ub_view_position + ray_dir * mix(span.x, span.y, seed);
+                vec4 color_density = ub_fog_volume_color_density[i];
+                float density = color_density.w * fog_volume_edge(i, p) * fog_density_noise(p);
+                float transmittance = exp(-density * (span.y - span.x));
+                vec3 caustics = ub_fog_volume_params[i].z > 0.5 ? ub_caustics.rgb : vec3(0.0);
+                vec3 fog_color = sample_fog_at(p, 3.0, color_density.rgb, vec3(0.0, 1.0, 0.0), screen_uv, V, caustics);
+                color = mix(fog_color, color, transmittance);
+            }
+        }
+    }
+    return color;
+}
+#endif // FOG_VOLUMES
+
 void main() {
     vec4 base_color = ub_base_color * to_linear(texture2D(ub_base_color_texture, uv_0));
     float blender_exposure = 0.2; // TODO set on camera
@@ -337,6 +470,10 @@ void main() {
     {
         #ifdef CASCADE
         vec4 col_shad = sample_cascade_stochastic(ws_position, normal, screen_uv, diffuse_color);
+        if (cascade_blend > 0.0) {
+            vec4 blend_col_shad = sample_blend_cascade_stochastic(ws_position, normal, screen_uv, diffuse_color);
+            col_shad = mix(col_shad, blend_col_shad, cascade_blend);
+        }
         output_color += col_shad.rgb;
         dir_shadow = col_shad.w;
 
@@ -349,11 +486,7 @@ void main() {
             env_occ, ub_diffuse_transmission, screen_uv, ub_view_resolution, ws_position, dir_shadow);
 
     #ifdef THERES_CAUSTICS
-
-    // TODO use time, keep in sync with sample_fog
-    vec3 pos = vec3(ws_position.x, -0.1 * ub_time, ws_position.z);
-    vec3 o = vec3(1.0, 0.0, 1.0) * 0.02;
-    float w = water_caustics(pos);
+    float w = caustics_pattern(ws_position);
     float intensity = exp(w * 4. - 1.);
     output_color += diffuse_color * intensity * 40.0 * ub_caustics.rgb;
     #endif
@@ -365,22 +498,49 @@ void main() {
         vec3 fog_color = sample_fog(3.0, seed, fog_solid_color, vec3(0.0, 1.0, 0.0), screen_uv, V);
 
         float frag_dist = length(ub_view_position - ws_position);
-        float f = min(frag_dist * ub_fog_color.w, 1.0);
+        float density = ub_fog_color.w * fog_density_noise(mix(ub_view_position, ws_position, seed));
+        float f = min(frag_dist * density, 1.0);
         output_color = fog_color * f + (1.0 - f) * output_color;
         float distance_fog = 0.5;
         output_color += fog_solid_color * frag_dist * distance_fog;
     }
     #endif // THERES_FOG
 
+    #ifdef FOG_VOLUMES
+    // Blended surfaces would fog what's behind them again.
+    if (!ub_alpha_blend) {
+        float frag_dist = length(ub_view_position - ws_position);
+        float seed = hash(screen_uv + hash(ub_frame - 234.567));
+        output_color = apply_fog_volumes(output_color, seed, frag_dist, screen_uv, V);
+    }
+    #endif // FOG_VOLUMES
+
     gl_FragColor = vec4(ub_view_exposure * output_color * blender_exposure, base_color.a);
     #ifdef WRITE_REFLECTION
     gl_FragColor.rgb = reversible_tonemap(gl_FragColor.rgb);
     #else
+    #ifdef HDR_OUTPUT
+    #ifndef HDR_FLOAT_TARGET
+    gl_FragColor.rgb = hdr_encode(gl_FragColor.rgb); // tonemapped in post_process.frag
+    #endif // HDR_FLOAT_TARGET
+    // Alpha 0 marks the pixels left at the ClearColor, which skip the tonemap.
+    if (!ub_alpha_blend) {
+        gl_FragColor.a = 1.0;
+    }
+    #else
     gl_FragColor.rgb = agx_tonemapping(gl_FragColor.rgb); // in: linear, out: srgb
+    #endif // HDR_OUTPUT
     //gl_FragColor.rgb = from_linear(gl_FragColor.rgb); // in: linear, out: srgb
     //gl_FragColor.rgb = mix(gl_FragColor.rgb, post_tonemap_emissive, emissive_v);
     #endif // WRITE_REFLECTION
+    #ifdef CASCADE_DEBUG
+    gl_FragColor.rgb = mix(gl_FragColor.rgb, cascade_debug_color, 0.75);
+    #endif // CASCADE_DEBUG
+    #ifdef HDR_FLOAT_TARGET
+    gl_FragColor = vec4(max(gl_FragColor.rgb, vec3(0.0)), clamp(gl_FragColor.a, 0.0, 1.0));
+    #else
     gl_FragColor = clamp(gl_FragColor, vec4(0.0), vec4(1.0));
+    #endif // HDR_FLOAT_TARGET
 
     #endif // NOT RENDER_DEPTH_ONLY
 }
@@ -0,0 +1,291 @@
+//! `--bake <scene|all>`: loads each requested scene without a window, bakes all of its cascades
+//! into `assets/bake/` and exits with a report. The exit code is non zero if any cascade failed.
+//! With `--reference-pt` each scene's spawn view is also rendered by `reference_pt` after baking.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    prelude::*,
+    scene::{SceneInstance, SceneSpawner},
+};
+
+use crate::{
+    SceneState,
+    cascade::CascadeInput,
+    cascade_manifest::SceneCascadeManifest,
+    despawn_scene_contents,
+    probe_baker::{
+        CascadeBakeOutcome, CascadeBakeResult, NeedsProbeBake, ProbeBakeReport, bake_probes,
+    },
+    reference_pt::ReferencePtRequest,
+    scene_falling::load_falling,
+    scene_hallway::load_hallway,
+    scene_store::load_store,
+    scene_temple::load_temple,
+    scene_underwater::load_underwater,
+};
+
+/// Scenes are baked after their assets are loaded and they have been stable for this many frames,
+/// so observers like `blender_cascades` have spawned their cascades.
+const SETTLE_FRAMES: u32 = 10;
+const SCENE_LOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BakeScene {
+    Store,
+    Hallway,
+    Temple,
+    Underwater,
+    Falling,
+}
+
+impl BakeScene {
+    pub const ALL: [BakeScene; 5] = [
+        BakeScene::Store,
+        BakeScene::Hallway,
+        BakeScene::Temple,
+        BakeScene::Underwater,
+        BakeScene::Falling,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BakeScene::Store => "store",
+            BakeScene::Hallway => "hallway",
+            BakeScene::Temple => "temple",
+            BakeScene::Underwater => "underwater",
+            BakeScene::Falling => "falling",
+        }
+    }
+
+    fn load(self, commands: &mut Commands) {
+        commands.run_system_cached(despawn_scene_contents);
+        match self {
+            BakeScene::Store => commands.run_system_cached(load_store),
+            BakeScene::Hallway => commands.run_system_cached(load_hallway),
+            BakeScene::Temple => commands.run_system_cached(load_temple),
+            BakeScene::Underwater => commands.run_system_cached(load_underwater),
+            BakeScene::Falling => commands.run_system_cached(load_falling),
+        }
+    }
+}
+
+/// Parses `all` or a comma separated list of scene names.
+pub fn parse_bake_scenes(arg: &str) -> Result<Vec<BakeScene>, String> {
+    if arg.eq_ignore_ascii_case("all") {
+        return Ok(BakeScene::ALL.to_vec());
+    }
+    arg.split(',')
+        .map(|name| {
+            let name = name.trim();
+            BakeScene::ALL
+                .into_iter()
+                .find(|scene| scene.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    let names: Vec<_> = BakeScene::ALL.iter().map(|s| s.name()).collect();
+                    format!(
+                        "unknown scene '{name}', expected all or one of {}",
+                        names.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+pub struct BakeCliPlugin {
+    pub scenes: Vec<BakeScene>,
+    /// Render a reference image of each scene once it's baked.
+    pub reference_pt: bool,
+}
+
+impl Plugin for BakeCliPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BakeQueue {
+            pending: self.scenes.iter().copied().collect(),
+            reference_pt: self.reference_pt,
+            current: None,
+            results: Vec::new(),
+            start: Instant::now(),
+        })
+        .add_systems(
+            Update,
+            drive_bake
+                .before(bake_probes)
+                .run_if(not(in_state(SceneState::Loading))),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct BakeQueue {
+    pending: VecDeque<BakeScene>,
+    current: Option<CurrentBake>,
+    reference_pt: bool,
+    results: Vec<(BakeScene, SceneBakeResult)>,
+    start: Instant,
+}
+
+struct CurrentBake {
+    scene: BakeScene,
+    started: Instant,
+    ready_frames: u32,
+    phase: BakePhase,
+}
+
+#[derive(PartialEq, Eq)]
+enum BakePhase {
+    Loading,
+    Baking,
+    Rendering,
+}
+
+enum SceneBakeResult {
+    Cascade(CascadeBakeResult),
+    NoCascades,
+    LoadTimeout,
+}
+
+fn drive_bake(
+    mut commands: Commands,
+    mut queue: ResMut<BakeQueue>,
+    mut report: ResMut<ProbeBakeReport>,
+    scene_roots: Query<(&SceneRoot, Option<&SceneInstance>)>,
+    manifests: Query<&SceneCascadeManifest>,
+    cascades: Query<Entity, With<CascadeInput>>,
+    pending_bakes: Query<(), With<NeedsProbeBake>>,
+    reference_request: Option<Res<ReferencePtRequest>>,
+    scene_spawner: Res<SceneSpawner>,
+    asset_server: Res<AssetServer>,
+    mut exit: MessageWriter<AppExit>,
+) {
+    let queue = &mut *queue;
+    let Some(current) = &mut queue.current else {
+        match queue.pending.pop_front() {
+            Some(scene) => {
+                info!("Loading {} for baking", scene.name());
+                scene.load(&mut commands);
+                queue.current = Some(CurrentBake {
+                    scene,
+                    started: Instant::now(),
+                    ready_frames: 0,
+                    phase: BakePhase::Loading,
+                });
+            }
+            None => {
+                exit.write(finish(queue));
+            }
+        }
+        return;
+    };
+
+    match current.phase {
+        BakePhase::Loading => (),
+        BakePhase::Baking => {
+            if pending_bakes.is_empty() {
+                queue.results.extend(
+                    report
+                        .results
+                        .drain(..)
+                        .map(|r| (current.scene, SceneBakeResult::Cascade(r))),
+                );
+                if queue.reference_pt {
+                    commands.insert_resource(ReferencePtRequest {
+                        scene_name: Some(current.scene.name().to_string()),
+                    });
+                    current.phase = BakePhase::Rendering;
+                } else {
+                    queue.current = None;
+                }
+            }
+            return;
+        }
+        BakePhase::Rendering => {
+            if reference_request.is_none() {
+                queue.current = None;
+            }
+            return;
+        }
+    }
+
+    let settled = |id: UntypedAssetId| {
+        asset_server.is_loaded_with_dependencies(id) || asset_server.load_state(id).is_failed()
+    };
+    let loaded = scene_roots.iter().all(|(root, instance)| {
+        settled(root.0.id().untyped())
+            && instance.is_some_and(|instance| scene_spawner.instance_is_ready(**instance))
+    }) && manifests.iter().all(|m| settled(m.0.id().untyped()));
+    current.ready_frames = if loaded { current.ready_frames + 1 } else { 0 };
+
+    if current.ready_frames >= SETTLE_FRAMES {
+        if cascades.is_empty() {
+            queue
+                .results
+                .push((current.scene, SceneBakeResult::NoCascades));
+            queue.current = None;
+            return;
+        }
+        info!(
+            "Baking {} cascades of {}",
+            cascades.iter().len(),
+            current.scene.name()
+        );
+        for entity in &cascades {
+            commands.entity(entity).insert(NeedsProbeBake);
+        }
+        current.phase = BakePhase::Baking;
+    } else if current.started.elapsed() > SCENE_LOAD_TIMEOUT {
+        queue
+            .results
+            .push((current.scene, SceneBakeResult::LoadTimeout));
+        queue.current = None;
+    }
+}
+
+fn finish(queue: &BakeQueue) -> AppExit {
+    let mut failed = 0;
+    let mut baked = 0;
+    let mut skipped = 0;
+    let mut lines = vec![String::from("Probe bake report:")];
+    for (scene, result) in &queue.results {
+        let scene = scene.name();
+        lines.push(match result {
+            SceneBakeResult::Cascade(r) => match &r.outcome {
+                CascadeBakeOutcome::Baked(reason) => {
+                    baked += 1;
+                    format!(
+                        "  {scene}: {} baked ({reason}), {} probes in {:.2}s",
+                        r.name,
+                        r.probes,
+                        r.duration.as_secs_f32()
+                    )
+                }
+                CascadeBakeOutcome::Unchanged => {
+                    skipped += 1;
+                    format!("  {scene}: {} skipped, inputs unchanged", r.name)
+                }
+                CascadeBakeOutcome::Failed(e) => {
+                    failed += 1;
+                    format!("  {scene}: {} FAILED {e}", r.name)
+                }
+            },
+            SceneBakeResult::NoCascades => format!("  {scene}: no cascades"),
+            SceneBakeResult::LoadTimeout => {
+                failed += 1;
+                format!("  {scene}: FAILED timed out loading the scene")
+            }
+        });
+    }
+    lines.push(format!(
+        "{baked} cascades baked, {skipped} skipped, {failed} failed in {:.2}s",
+        queue.start.elapsed().as_secs_f32()
+    ));
+    info!("{}", lines.join("\n"));
+    if failed == 0 {
+        AppExit::Success
+    } else {
+        AppExit::error()
+    }
+}
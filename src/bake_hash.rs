@@ -0,0 +1,175 @@
+//! Hash of everything a cascade's bake depends on, stored next to the bake as
+//! `bake/<name>.bakehash` so unchanged cascades can be skipped.
+
+use std::{
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    cascade::{CascadeInput, transform_aabb},
+    probe_baker::{GI_ATLAS_SIZE, PROBE_SIZE, ProbeBakeSettings},
+    rt_scene::{RtLight, RtLightKind, RtScene},
+};
+
+/// Bump when the baker changes in a way that should invalidate existing bakes.
+const BAKE_HASH_VERSION: u32 = 2;
+
+pub fn bake_hash_path(name: &str) -> String {
+    format!("bake/{name}.bakehash")
+}
+
+/// 64 bit FNV-1a.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn hash_vec3a(h: &mut Fnv1a, v: Vec3A) {
+    v.to_array().map(f32::to_bits).hash(h);
+}
+
+fn hash_one(f: impl FnOnce(&mut Fnv1a)) -> u64 {
+    let mut h = Fnv1a::default();
+    f(&mut h);
+    h.finish()
+}
+
+/// Region whose geometry and lights are considered part of the cascade's inputs, the cascade
+/// enlarged by its influence range like `enlarged_cascade_local_aabb`.
+fn cascade_influence_aabb(input: &CascadeInput) -> obvhs::aabb::Aabb {
+    let spacing = input.resolution * 2.0;
+    let local = obvhs::aabb::Aabb::new(input.ws_aabb.min - spacing, input.ws_aabb.max + spacing);
+    transform_aabb(Mat4::from_quat(input.rotation), local)
+}
+
+fn light_reaches(light: &RtLight, aabb: &obvhs::aabb::Aabb) -> bool {
+    let (position, range) = match light.kind {
+        RtLightKind::Point { position, range }
+        | RtLightKind::Spot {
+            position, range, ..
+        } => (position, range),
+        RtLightKind::Directional { .. } => return true,
+    };
+    position.clamp(aabb.min, aabb.max).distance(position) <= range
+}
+
+fn hash_light(h: &mut Fnv1a, light: &RtLight) {
+    match light.kind {
+        RtLightKind::Point { position, range } => {
+            0u8.hash(h);
+            hash_vec3a(h, position);
+            range.to_bits().hash(h);
+        }
+        RtLightKind::Spot {
+            position,
+            direction,
+            range,
+            cos_inner,
+            cos_outer,
+        } => {
+            1u8.hash(h);
+            hash_vec3a(h, position);
+            hash_vec3a(h, direction);
+            [range, cos_inner, cos_outer].map(f32::to_bits).hash(h);
+        }
+        RtLightKind::Directional { direction } => {
+            2u8.hash(h);
+            hash_vec3a(h, direction);
+        }
+    }
+    hash_vec3a(h, light.intensity);
+    light.realtime.hash(h);
+}
+
+/// Hashes the cascade layout, the bake settings, the env color and the triangles, materials and
+/// lights that reach the cascade. Triangles and lights are combined order independently since
+/// query order isn't stable between runs.
+pub fn cascade_bake_hash(
+    scene: &RtScene,
+    input: &CascadeInput,
+    settings: &ProbeBakeSettings,
+) -> u64 {
+    let region = cascade_influence_aabb(input);
+
+    let geometry = scene
+        .triangles
+        .iter()
+        .zip(&scene.triangle_materials)
+        .filter(|(tri, _)| {
+            let mut aabb = obvhs::aabb::Aabb::empty();
+            for v in tri.iter() {
+                aabb.extend(*v);
+            }
+            aabb.intersection(&region).valid()
+        })
+        .fold(0u64, |sum, (tri, material)| {
+            let material = &scene.materials[*material as usize];
+            sum.wrapping_add(hash_one(|h| {
+                tri.iter().for_each(|v| hash_vec3a(h, *v));
+                hash_vec3a(h, material.albedo);
+                hash_vec3a(h, material.emissive);
+            }))
+        });
+
+    let lights = scene
+        .lights
+        .iter()
+        .filter(|light| light_reaches(light, &region))
+        .fold(0u64, |sum, light| {
+            sum.wrapping_add(hash_one(|h| hash_light(h, light)))
+        });
+
+    hash_one(|h| {
+        BAKE_HASH_VERSION.hash(h);
+        [
+            PROBE_SIZE,
+            GI_ATLAS_SIZE,
+            settings.rays_per_probe,
+            settings.bounces,
+        ]
+        .hash(h);
+        let repair = &settings.repair;
+        repair.dilation_passes.hash(h);
+        [repair.buried_backface_ratio, repair.outlier_ratio]
+            .map(f32::to_bits)
+            .hash(h);
+        input.name.hash(h);
+        hash_vec3a(h, input.ws_aabb.min);
+        hash_vec3a(h, input.ws_aabb.max);
+        hash_vec3a(h, input.resolution);
+        input.rotation.to_array().map(f32::to_bits).hash(h);
+        hash_vec3a(h, scene.env_color);
+        geometry.hash(h);
+        lights.hash(h);
+    })
+}
+
+pub fn read_bake_hash(assets_dir: &Path, name: &str) -> Option<u64> {
+    let text = std::fs::read_to_string(assets_dir.join(bake_hash_path(name))).ok()?;
+    u64::from_str_radix(text.trim(), 16).ok()
+}
+
+pub fn write_bake_hash(assets_dir: &Path, name: &str, hash: u64) -> std::io::Result<()> {
+    std::fs::write(
+        assets_dir.join(bake_hash_path(name)),
+        format!("{hash:016x}\n"),
+    )
+}
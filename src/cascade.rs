@@ -1,4 +1,9 @@
+use std::{f32::consts::PI, time::Duration};
+
 use bevy::{
+    asset::LoadState,
+    camera::primitives::Aabb,
+    ecs::system::SystemParam,
     image::{
         ImageAddressMode, ImageFilterMode, ImageLoaderSettings, ImageSampler,
         ImageSamplerDescriptor,
@@ -13,9 +18,39 @@ pub struct ConvertCascadePlugin;
 
 impl Plugin for ConvertCascadePlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<CascadeBvh>()
+            .init_resource::<CascadeFallback>()
+            .init_asset::<CascadeBake>()
+            .init_asset_loader::<CascadeBakeLoader>()
+            .add_systems(
+                PostUpdate,
+                generate_cascade_data.in_set(RenderSet::Pipeline),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    check_cascade_images,
+                    update_cascade_bvh,
+                    update_selected_cascades,
+                )
+                    .chain()
+                    .in_set(RenderSet::Prepare),
+            )
+            .add_systems(EguiPrimaryContextPass, cascade_warning_overlay);
+
+        #[cfg(feature = "dev")]
         app.add_systems(
             PostUpdate,
-            generate_cascade_data.in_set(RenderSet::Pipeline),
+            reload_cascade_extras
+                .before(reload_cascade_bakes)
+                .in_set(RenderSet::Pipeline),
+        );
+        #[cfg(any(feature = "dev", feature = "asset_baking"))]
+        app.add_systems(
+            PostUpdate,
+            reload_cascade_bakes
+                .before(generate_cascade_data)
+                .in_set(RenderSet::Pipeline),
         );
     }
 }
@@ -48,38 +83,138 @@ pub fn blender_cascades(
         if let Ok((entity, name, trans, extras)) = gltf_extras.get(entity)
             && name.contains("BAKE")
         {
-            let extras: ProbeBakeExtras = serde_json::from_str(&extras.value).unwrap();
-            if let Some(bake_res) = extras.probe_bake_res {
-                let scale: Vec3A = trans.scale.into();
-                let position = trans.translation.to_vec3a();
-                let start = position - scale;
-                let end = position + scale;
+            if let Some((ws_aabb, resolution, rotation)) =
+                parse_cascade_extras(trans, extras).unwrap()
+            {
                 let mut full_name = name_prefix.clone();
                 full_name.push_str(name);
                 let mut ecmds = commands.entity(entity);
                 ecmds
                     .insert(CascadeInput {
                         name: full_name,
-                        ws_aabb: obvhs::aabb::Aabb::new(start, end),
-                        resolution: vec3a(bake_res[0], bake_res[1], bake_res[2]),
+                        ws_aabb,
+                        resolution,
+                        rotation,
                     })
-                    .remove::<CascadeUniform>();
+                    .remove::<(CascadeUniform, CascadeBakeHandle)>();
+            }
+        }
+    }
+}
+
+/// Cascade bounds, probe spacing and orientation from a `BAKE` node. The node scale is the half
+/// extent. The bounds are in cascade space, see `CascadeInput`.
+fn parse_cascade_extras(
+    trans: &Transform,
+    extras: &GltfExtras,
+) -> serde_json::Result<Option<(obvhs::aabb::Aabb, Vec3A, Quat)>> {
+    let extras: ProbeBakeExtras = serde_json::from_str(&extras.value)?;
+    Ok(extras.probe_bake_res.map(|bake_res| {
+        let scale: Vec3A = trans.scale.into();
+        let position = trans.rotation.inverse() * trans.translation.to_vec3a();
+        (
+            obvhs::aabb::Aabb::new(position - scale, position + scale),
+            vec3a(bake_res[0], bake_res[1], bake_res[2]),
+            trans.rotation,
+        )
+    }))
+}
 
-                #[cfg(feature = "asset_baking")]
-                ecmds.remove::<CascadeData>();
+/// Drops the uniforms of cascades whose bake textures changed on disk, or whose packed bake
+/// showed up after falling back to the png pair, so `generate_cascade_data` rebuilds them in place.
+#[cfg(any(feature = "dev", feature = "asset_baking"))]
+fn reload_cascade_bakes(
+    mut commands: Commands,
+    mut bake_events: MessageReader<AssetEvent<CascadeBake>>,
+    mut image_events: MessageReader<AssetEvent<Image>>,
+    cascades: Query<(
+        Entity,
+        &CascadeInput,
+        &CascadeUniform,
+        Option<&CascadeBakeHandle>,
+    )>,
+) {
+    let modified_bakes: Vec<_> = bake_events
+        .read()
+        .filter_map(|e| match e {
+            AssetEvent::Modified { id } | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    let modified_images: Vec<_> = image_events
+        .read()
+        .filter_map(|e| match e {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    if modified_bakes.is_empty() && modified_images.is_empty() {
+        return;
+    }
+    for (entity, input, cascade, bake_handle) in &cascades {
+        let bake_modified = bake_handle.is_some_and(|h| modified_bakes.contains(&h.0.id()));
+        let images_modified = modified_images.contains(&cascade.probes_gi.id())
+            || modified_images.contains(&cascade.probes_id.id());
+        if bake_modified || images_modified {
+            info!("Reloading probe bake for cascade {}", input.name);
+            commands
+                .entity(entity)
+                .remove::<(CascadeUniform, CascadeViewUniform)>();
+        }
+    }
+}
+
+/// Re-reads `probe bake res` and the node bounds when the scene's gltf is reloaded.
+#[cfg(feature = "dev")]
+fn reload_cascade_extras(
+    mut commands: Commands,
+    mut cascades: Query<
+        (Entity, &mut CascadeInput, &Transform, &GltfExtras),
+        Or<(Changed<GltfExtras>, Changed<Transform>)>,
+    >,
+) {
+    for (entity, mut input, trans, extras) in &mut cascades {
+        let (ws_aabb, resolution, rotation) = match parse_cascade_extras(trans, extras) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => {
+                warn!("Cascade {} no longer has probe bake res", input.name);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to parse extras for cascade {}: {e}", input.name);
+                continue;
             }
+        };
+        if input.ws_aabb == ws_aabb && input.resolution == resolution && input.rotation == rotation
+        {
+            continue;
         }
+        info!("Cascade {} extras changed, rebuilding", input.name);
+        input.ws_aabb = ws_aabb;
+        input.resolution = resolution;
+        input.rotation = rotation;
+        // Keep the bake handle, generate_cascade_data re-checks it against the new layout.
+        commands
+            .entity(entity)
+            .remove::<(CascadeUniform, CascadeViewUniform)>();
     }
 }
 
+use bevy_egui::{EguiContexts, EguiPrimaryContextPass, egui};
 use bgl2::render::RenderSet;
-#[cfg(feature = "asset_baking")]
-use light_volume_baker::CascadeData;
-use obvhs::ray::Ray;
+use obvhs::{
+    BvhBuildParams,
+    bvh2::{Bvh2, builder::build_bvh2},
+    ray::Ray,
+};
 use serde::Deserialize;
 use uniform_set_derive::UniformSet;
+use wgpu_types::TextureFormat;
 
-use crate::draw_debug::DebugLines;
+use crate::{
+    cascade_bake::{CascadeBake, CascadeBakeLoader, cascade_bake_path},
+    draw_debug::DebugLines,
+};
 
 pub fn transform_aabb(world_from_local: Mat4, aabb: obvhs::aabb::Aabb) -> obvhs::aabb::Aabb {
     let min = aabb.min;
@@ -96,31 +231,73 @@ pub fn transform_aabb(world_from_local: Mat4, aabb: obvhs::aabb::Aabb) -> obvhs:
     aabb
 }
 
+/// The probe grid bounds in cascade space.
+pub fn cascade_local_aabb(cascade: &CascadeUniform) -> obvhs::aabb::Aabb {
+    obvhs::aabb::Aabb::new(
+        cascade.cascade_position.into(),
+        (cascade.cascade_position + cascade.cascade_res * cascade.cascade_spacing).into(),
+    )
+}
+
+/// Oversize the cascade_local_aabb to include the full infulence range
+pub fn enlarged_cascade_local_aabb(cascade: &CascadeUniform) -> obvhs::aabb::Aabb {
+    let spacing = cascade.cascade_spacing.to_vec3a() * 2.0;
+    let mut enlarged_cascade_aabb = cascade_local_aabb(cascade);
+    enlarged_cascade_aabb.min -= spacing;
+    enlarged_cascade_aabb.max += spacing;
+    enlarged_cascade_aabb
+}
+
+pub fn world_from_cascade(cascade: &CascadeUniform) -> Mat4 {
+    cascade.cascade_from_world.inverse()
+}
+
+/// World space bounds of the (possibly rotated) cascade.
+pub fn cascade_aabb(cascade: &CascadeUniform) -> obvhs::aabb::Aabb {
+    transform_aabb(world_from_cascade(cascade), cascade_local_aabb(cascade))
+}
+
+/// World space bounds of the (possibly rotated) influence range.
+pub fn enlarged_cascade_aabb(cascade: &CascadeUniform) -> obvhs::aabb::Aabb {
+    transform_aabb(
+        world_from_cascade(cascade),
+        enlarged_cascade_local_aabb(cascade),
+    )
+}
+
+/// The cascades used for a draw. `blend` is the weight of `secondary`, 0.0 when only `primary` is
+/// used, up to 0.5 when the draw is at the edge of `primary`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CascadeSelection {
+    pub primary: u32,
+    pub secondary: u32,
+    pub blend: f32,
+}
+
+/// Returns the best cascades for the draw. Cascades are given with their index so a subset of
+/// candidates can be tested. Tests are done in each cascade's space against the draw bounds
+/// rotated into it.
 pub fn select_cascade<'a, I>(
     cascades: I,
     draw_aabb: obvhs::aabb::Aabb,
     #[allow(unused)] mut debug: &mut DebugLines,
-) -> u32
+) -> CascadeSelection
 where
-    I: IntoIterator<Item = &'a CascadeUniform>,
+    I: IntoIterator<Item = (usize, &'a CascadeUniform)>,
 {
-    // TODO cache selected cascade
-
-    let draw_size = draw_aabb.diagonal().length();
     let draw_center = draw_aabb.center();
     let mut draw_dist_to_cascade = f32::MAX;
     let mut best_cascade = 0;
     let mut best_relative_res = 0.0;
-    for (i, cascade) in cascades.into_iter().enumerate() {
-        let cascade_aabb = obvhs::aabb::Aabb::new(
-            cascade.cascade_position.into(),
-            (cascade.cascade_position + cascade.cascade_res * cascade.cascade_spacing).into(),
-        );
-        // Oversize the cascade_aabb to include the full infulence range
-        let spacing = cascade.cascade_spacing.to_vec3a() * 2.0;
-        let mut enlarged_cascade_aabb = cascade_aabb;
-        enlarged_cascade_aabb.min -= spacing;
-        enlarged_cascade_aabb.max += spacing;
+    let mut best_cascade_ref = None;
+    // Only cascades whose influence region overlaps the draw are blend candidates.
+    let mut overlapping = Vec::new();
+    for (i, cascade) in cascades {
+        let cascade_aabb = cascade_local_aabb(cascade);
+        let enlarged_cascade_aabb = enlarged_cascade_local_aabb(cascade);
+        let draw_aabb = transform_aabb(cascade.cascade_from_world, draw_aabb);
+        let draw_size = draw_aabb.diagonal().length();
+        let draw_center = draw_aabb.center();
 
         let outside_weight = 100.0; // TODO do better
         let cascade_intersection = cascade_aabb.intersection(&draw_aabb);
@@ -134,8 +311,10 @@ where
                 (cascade_aabb.center() - draw_center).normalize_or_zero(),
             )) * outside_weight
         };
-        //debug.aabb(cascade_aabb, vec3(1.0, 0.0, 1.0));
-        //debug.aabb(draw_aabb, vec3(1.0, 0.0, 0.0));
+
+        if enlarged_cascade_intersection.valid() {
+            overlapping.push((i, dist_to_cascade));
+        }
 
         if dist_to_cascade < draw_dist_to_cascade
             || (dist_to_cascade < draw_dist_to_cascade * 1.1
@@ -145,16 +324,326 @@ where
             draw_dist_to_cascade = dist_to_cascade;
             best_cascade = i;
             best_relative_res = relative_res;
+            best_cascade_ref = Some(cascade);
         }
     }
-    best_cascade as u32
+
+    let mut selection = CascadeSelection {
+        primary: best_cascade as u32,
+        secondary: best_cascade as u32,
+        blend: 0.0,
+    };
+    let Some(best) = best_cascade_ref else {
+        return selection;
+    };
+    if let Some((secondary, _)) = overlapping
+        .into_iter()
+        .filter(|(i, _)| *i != best_cascade)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+    {
+        // Fade in the secondary cascade as the draw approaches the edge of the primary. The margin
+        // matches the enlarged influence region used above.
+        let margin = (best.cascade_spacing * 2.0).max_element();
+        let depth = depth_inside_aabb(
+            cascade_local_aabb(best),
+            best.cascade_from_world.transform_point3a(draw_center),
+        );
+        selection.secondary = secondary as u32;
+        selection.blend = 0.5 * (1.0 - depth / margin).clamp(0.0, 1.0);
+    }
+    selection
 }
 
+/// Distance from `p` to the closest face of `aabb`, negative if `p` is outside.
+fn depth_inside_aabb(aabb: obvhs::aabb::Aabb, p: Vec3A) -> f32 {
+    (p - aabb.min).min(aabb.max - p).min_element()
+}
+
+/// All cascades in the scene, in a BVH over their enlarged influence regions. Rebuilt only when the
+/// set of cascades changes. Indices returned from `select` index into `entities`/`uniforms`.
+#[derive(Resource, Default)]
+pub struct CascadeBvh {
+    pub entities: Vec<Entity>,
+    pub uniforms: Vec<CascadeUniform>,
+    pub view_uniforms: Vec<CascadeViewUniform>,
+    pub blend_uniforms: Vec<CascadeBlendUniform>,
+    /// Bumped every rebuild so cached `SelectedCascade`s know to reselect.
+    pub generation: u32,
+    bvh: Option<Bvh2>,
+}
+
+impl CascadeBvh {
+    pub fn select(&self, draw_aabb: obvhs::aabb::Aabb, debug: &mut DebugLines) -> CascadeSelection {
+        let Some(bvh) = &self.bvh else {
+            return CascadeSelection::default();
+        };
+        let mut candidates = Vec::new();
+        let mut stack = vec![0u32];
+        while let Some(node_index) = stack.pop() {
+            let node = &bvh.nodes[node_index as usize];
+            if !node.aabb.intersection(&draw_aabb).valid() {
+                continue;
+            }
+            if node.is_leaf() {
+                let start = node.first_index as usize;
+                let end = start + node.prim_count as usize;
+                candidates.extend(
+                    bvh.primitive_indices[start..end]
+                        .iter()
+                        .map(|i| *i as usize),
+                );
+            } else {
+                stack.push(node.first_index);
+                stack.push(node.first_index + 1);
+            }
+        }
+
+        if candidates.is_empty() {
+            // Not within any influence region, fall back to the closest cascade.
+            select_cascade(self.uniforms.iter().enumerate(), draw_aabb, debug)
+        } else {
+            select_cascade(
+                candidates.into_iter().map(|i| (i, &self.uniforms[i])),
+                draw_aabb,
+                debug,
+            )
+        }
+    }
+}
+
+/// Cached result of `select_cascade` for a mesh entity.
+#[derive(Component, Clone, Copy)]
+pub struct SelectedCascade {
+    pub selection: CascadeSelection,
+    pub generation: u32,
+}
+
+/// How draws are lit when a cascade's bake textures are missing or failed to load. Set per scene.
+#[derive(Resource, Clone)]
+pub struct CascadeFallback {
+    /// Flat ambient, in the same units as the probe lighting, used when there is no valid cascade
+    /// to fall back to or when `nearest_valid` is false.
+    pub ambient: Vec3,
+    /// Light draws from the nearest cascade that did load instead.
+    pub nearest_valid: bool,
+    /// Show an overlay listing the cascades with missing bakes.
+    pub warning_overlay: bool,
+}
+
+impl Default for CascadeFallback {
+    fn default() -> Self {
+        CascadeFallback {
+            ambient: Vec3::ZERO,
+            nearest_valid: true,
+            warning_overlay: cfg!(feature = "dev"),
+        }
+    }
+}
+
+#[derive(Component, Clone, Debug, PartialEq)]
+pub enum CascadeStatus {
+    Loading,
+    Valid,
+    /// Holds the paths that failed to load.
+    Missing(Vec<String>),
+}
+
+/// Tracks the load state of each cascade's bake textures.
+pub fn check_cascade_images(
+    mut commands: Commands,
+    cascades: Query<(
+        Entity,
+        &CascadeInput,
+        &CascadeUniform,
+        Option<&CascadeStatus>,
+    )>,
+    asset_server: Res<AssetServer>,
+) {
+    for (entity, input, uniform, status) in &cascades {
+        let mut missing = Vec::new();
+        let mut loading = false;
+        for handle in [&uniform.probes_gi, &uniform.probes_id] {
+            match asset_server.load_state(handle) {
+                LoadState::Failed(e) => missing.push(
+                    handle
+                        .path()
+                        .map(|p| p.to_string())
+                        .unwrap_or_else(|| e.to_string()),
+                ),
+                LoadState::Loaded => (),
+                LoadState::NotLoaded | LoadState::Loading => loading = true,
+            }
+        }
+        let new_status = if !missing.is_empty() {
+            CascadeStatus::Missing(missing)
+        } else if loading {
+            CascadeStatus::Loading
+        } else {
+            CascadeStatus::Valid
+        };
+        if status == Some(&new_status) {
+            continue;
+        }
+        if let CascadeStatus::Missing(paths) = &new_status {
+            error!(
+                "Cascade {} is missing bake textures: {}",
+                input.name,
+                paths.join(", ")
+            );
+        }
+        commands.entity(entity).insert(new_status);
+    }
+}
+
+pub fn update_cascade_bvh(
+    cascades: Query<(
+        Entity,
+        &CascadeUniform,
+        &CascadeViewUniform,
+        Option<&CascadeStatus>,
+    )>,
+    changed: Query<
+        (),
+        Or<(
+            Changed<CascadeUniform>,
+            Changed<CascadeViewUniform>,
+            Changed<CascadeStatus>,
+        )>,
+    >,
+    mut removed: RemovedComponents<CascadeUniform>,
+    fallback: Res<CascadeFallback>,
+    mut cascade_bvh: ResMut<CascadeBvh>,
+) {
+    let any_removed = removed.read().count() > 0;
+    if changed.is_empty() && !any_removed && !fallback.is_changed() {
+        return;
+    }
+
+    let is_missing =
+        |status: Option<&CascadeStatus>| matches!(status, Some(CascadeStatus::Missing(_)));
+    // Cascades that are missing their bake are left out so draws select the nearest valid one,
+    // unless there are none left.
+    let skip_missing =
+        fallback.nearest_valid && cascades.iter().any(|(_, _, _, status)| !is_missing(status));
+
+    let mut entities = Vec::new();
+    let mut uniforms = Vec::new();
+    let mut view_uniforms = Vec::new();
+    for (entity, uniform, view_uniform, status) in &cascades {
+        let missing = is_missing(status);
+        if missing && skip_missing {
+            continue;
+        }
+        let fallback_ambient = if missing {
+            fallback.ambient.extend(1.0)
+        } else {
+            Vec4::ZERO
+        };
+        entities.push(entity);
+        uniforms.push(CascadeUniform {
+            fallback_ambient,
+            ..uniform.clone()
+        });
+        view_uniforms.push(CascadeViewUniform {
+            fallback_ambient,
+            ..view_uniform.clone()
+        });
+    }
+    let aabbs = uniforms
+        .iter()
+        .map(enlarged_cascade_aabb)
+        .collect::<Vec<_>>();
+
+    cascade_bvh.bvh = (!aabbs.is_empty()).then(|| {
+        build_bvh2(
+            &aabbs,
+            BvhBuildParams::fast_build(),
+            &mut Duration::default(),
+        )
+    });
+    cascade_bvh.entities = entities;
+    cascade_bvh.blend_uniforms = uniforms.iter().map(Into::into).collect();
+    cascade_bvh.uniforms = uniforms;
+    cascade_bvh.view_uniforms = view_uniforms;
+    cascade_bvh.generation = cascade_bvh.generation.wrapping_add(1);
+}
+
+pub fn cascade_warning_overlay(
+    mut contexts: EguiContexts,
+    cascades: Query<(&CascadeInput, &CascadeStatus)>,
+    fallback: Res<CascadeFallback>,
+) {
+    if !fallback.warning_overlay {
+        return;
+    }
+    let missing = cascades
+        .iter()
+        .filter_map(|(input, status)| match status {
+            CascadeStatus::Missing(paths) => Some((input, paths)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return;
+    }
+    let Ok(ctx) = contexts.ctx_mut() else {
+        return;
+    };
+    egui::Window::new("MISSING PROBE BAKES")
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-10.0, -10.0))
+        .resizable(false)
+        .collapsible(true)
+        .show(ctx, |ui| {
+            for (input, paths) in missing {
+                ui.colored_label(
+                    egui::Color32::from_rgb(255, 120, 60),
+                    format!("{}: {}", input.name, paths.join(", ")),
+                );
+            }
+        });
+}
+
+/// Reselects the cascade for meshes that moved, or for all meshes when the cascades changed.
+pub fn update_selected_cascades(
+    mut commands: Commands,
+    meshes: Query<
+        (
+            Entity,
+            Ref<GlobalTransform>,
+            Ref<Aabb>,
+            Option<&SelectedCascade>,
+        ),
+        With<MeshMaterial3d<StandardMaterial>>,
+    >,
+    cascade_bvh: Res<CascadeBvh>,
+    mut debug: ResMut<DebugLines>,
+) {
+    for (entity, transform, aabb, selected) in &meshes {
+        let up_to_date = selected.is_some_and(|s| s.generation == cascade_bvh.generation)
+            && !transform.is_changed()
+            && !aabb.is_changed();
+        if up_to_date {
+            continue;
+        }
+        let draw_aabb = transform_aabb(
+            transform.to_matrix(),
+            obvhs::aabb::Aabb::new(aabb.min(), aabb.max()),
+        );
+        commands.entity(entity).insert(SelectedCascade {
+            selection: cascade_bvh.select(draw_aabb, &mut debug),
+            generation: cascade_bvh.generation,
+        });
+    }
+}
+
+/// `ws_aabb` is in cascade space, which is world space rotated by the inverse of `rotation`. With
+/// the default identity rotation that's just world space.
 #[derive(Component, Clone)]
 pub struct CascadeInput {
     pub name: String,
     pub ws_aabb: obvhs::aabb::Aabb,
     pub resolution: Vec3A,
+    pub rotation: Quat,
 }
 
 #[derive(UniformSet, Component, Clone)]
@@ -169,6 +658,46 @@ pub struct CascadeUniform {
     pub probe_size: f32,
     pub gi_texel: f32,
     pub id_texel: Vec2,
+    /// Flat ambient used instead of the probes when w is 1.0, see `CascadeFallback`.
+    pub fallback_ambient: Vec4,
+    /// Rotates world space into the space the probe grid is axis aligned in.
+    pub cascade_from_world: Mat4,
+}
+
+/// The secondary cascade of a draw, blended in by `cascade_blend`.
+#[derive(UniformSet, Clone)]
+#[uniform_set(prefix = "ubb_")]
+pub struct CascadeBlendUniform {
+    pub probes_gi: Handle<Image>,
+    pub probes_id: Handle<Image>,
+    pub cascade_position: Vec3,
+    pub cascade_res: Vec3,
+    pub cascade_spacing: Vec3,
+    /// Before padding
+    pub probe_size: f32,
+    pub gi_texel: f32,
+    pub id_texel: Vec2,
+    /// Flat ambient used instead of the probes when w is 1.0, see `CascadeFallback`.
+    pub fallback_ambient: Vec4,
+    /// Rotates world space into the space the probe grid is axis aligned in.
+    pub cascade_from_world: Mat4,
+}
+
+impl From<&CascadeUniform> for CascadeBlendUniform {
+    fn from(c: &CascadeUniform) -> Self {
+        CascadeBlendUniform {
+            probes_gi: c.probes_gi.clone(),
+            probes_id: c.probes_id.clone(),
+            cascade_position: c.cascade_position,
+            cascade_res: c.cascade_res,
+            cascade_spacing: c.cascade_spacing,
+            probe_size: c.probe_size,
+            gi_texel: c.gi_texel,
+            id_texel: c.id_texel,
+            fallback_ambient: c.fallback_ambient,
+            cascade_from_world: c.cascade_from_world,
+        }
+    }
 }
 
 // TODO UniformSet for newtype?
@@ -184,19 +713,33 @@ pub struct CascadeViewUniform {
     pub probe_size: f32,
     pub gi_texel: f32,
     pub id_texel: Vec2,
+    /// Flat ambient used instead of the probes when w is 1.0, see `CascadeFallback`.
+    pub fallback_ambient: Vec4,
+    /// Rotates world space into the space the probe grid is axis aligned in.
+    pub cascade_from_world: Mat4,
 }
 
 impl CascadeInput {
+    pub fn cascade_from_world(&self) -> Mat4 {
+        Mat4::from_quat(self.rotation.inverse())
+    }
+
     pub fn into_uniform(&self, asset_server: &AssetServer) -> CascadeUniform {
         let cascade_res = (self.ws_aabb.diagonal() / self.resolution).ceil();
         CascadeUniform {
             probes_gi: asset_server.load_with_settings(
-                format!("bake/probes_gi_{}.png", self.name),
-                |settings: &mut ImageLoaderSettings| settings.sampler = sampler_linear_clamp(),
+                probes_gi_path(&self.name),
+                |settings: &mut ImageLoaderSettings| {
+                    settings.sampler = sampler_linear_clamp();
+                    settings.is_srgb = false;
+                },
             ),
             probes_id: asset_server.load_with_settings(
-                format!("bake/probes_id_{}.png", self.name),
-                |settings: &mut ImageLoaderSettings| settings.sampler = sampler_nearest_clamp(),
+                probes_id_path(&self.name),
+                |settings: &mut ImageLoaderSettings| {
+                    settings.sampler = sampler_nearest_clamp();
+                    settings.is_srgb = false;
+                },
             ),
             cascade_position: self.ws_aabb.min.into(),
             cascade_res: cascade_res.into(),
@@ -204,6 +747,8 @@ impl CascadeInput {
             probe_size: 6.0,
             gi_texel: 1.0 / 2048.0,
             id_texel: vec2(1.0 / cascade_res.x, 1.0 / (cascade_res.y * cascade_res.z)),
+            fallback_ambient: Vec4::ZERO,
+            cascade_from_world: self.cascade_from_world(),
         }
     }
 
@@ -212,12 +757,18 @@ impl CascadeInput {
         let cascade_res = (self.ws_aabb.diagonal() / self.resolution).ceil();
         CascadeViewUniform {
             probes_gi: asset_server.load_with_settings(
-                format!("bake/probes_gi_{}.png", self.name),
-                |settings: &mut ImageLoaderSettings| settings.sampler = sampler_linear_clamp(),
+                probes_gi_path(&self.name),
+                |settings: &mut ImageLoaderSettings| {
+                    settings.sampler = sampler_linear_clamp();
+                    settings.is_srgb = false;
+                },
             ),
             probes_id: asset_server.load_with_settings(
-                format!("bake/probes_id_{}.png", self.name),
-                |settings: &mut ImageLoaderSettings| settings.sampler = sampler_nearest_clamp(),
+                probes_id_path(&self.name),
+                |settings: &mut ImageLoaderSettings| {
+                    settings.sampler = sampler_nearest_clamp();
+                    settings.is_srgb = false;
+                },
             ),
             cascade_position: self.ws_aabb.min.into(),
             cascade_res: cascade_res.into(),
@@ -225,39 +776,265 @@ impl CascadeInput {
             probe_size: 6.0,
             gi_texel: 1.0 / 2048.0,
             id_texel: vec2(1.0 / cascade_res.x, 1.0 / (cascade_res.y * cascade_res.z)),
+            fallback_ambient: Vec4::ZERO,
+            cascade_from_world: self.cascade_from_world(),
         }
     }
+}
 
-    #[cfg(feature = "asset_baking")]
-    pub fn into_cascade_data(&self) -> CascadeData {
-        let cascade_res = (self.ws_aabb.diagonal() / self.resolution).ceil();
-        CascadeData {
-            name: self.name.clone(),
-            ws_aabb: self.ws_aabb,
-            cascade_position: self.ws_aabb.min.into(),
-            cascade_res: cascade_res.into(),
-            cascade_spacing: self.resolution.into(),
-            probe_size: 6.0,
-            gi_texel: 1.0 / 2048.0,
-            id_texel: vec2(1.0 / cascade_res.x, 1.0 / (cascade_res.y * cascade_res.z)),
-        }
-    }
+pub fn probes_gi_path(name: &str) -> String {
+    format!("bake/probes_gi_{name}.png")
+}
+
+pub fn probes_id_path(name: &str) -> String {
+    format!("bake/probes_id_{name}.png")
 }
 
+/// The packed bake for this cascade, see `cascade_bake`. Kept after loading so the file stays
+/// watched for hot reload.
+#[derive(Component)]
+pub struct CascadeBakeHandle(pub Handle<CascadeBake>);
+
+/// Loads the packed bake for each cascade, falling back to the separate png pair if there isn't
+/// one. Bakes made for a different cascade layout are rejected.
 pub fn generate_cascade_data(
     mut commands: Commands,
-    input_probes: Query<(Entity, &CascadeInput), Without<CascadeUniform>>,
+    input_probes: Query<
+        (Entity, &CascadeInput, Option<&CascadeBakeHandle>),
+        Without<CascadeUniform>,
+    >,
     asset_server: Res<AssetServer>,
+    bakes: Res<Assets<CascadeBake>>,
 ) {
-    for (entity, input) in &input_probes {
+    for (entity, input, bake_handle) in &input_probes {
         let mut ecmds = commands.entity(entity);
-        ecmds.insert(input.into_uniform(&asset_server));
-        ecmds.insert(input.into_view_uniform(&asset_server));
-        #[cfg(feature = "asset_baking")]
-        ecmds.insert(input.into_cascade_data());
+        let bake = if let Some(bake_handle) = bake_handle {
+            match asset_server.load_state(&bake_handle.0) {
+                LoadState::Loaded => bakes.get(&bake_handle.0).and_then(|bake| {
+                    if let Err(e) = bake.header.matches(input) {
+                        error!(
+                            "Rejecting {} for cascade {}: {e}",
+                            cascade_bake_path(&input.name),
+                            input.name
+                        );
+                        None
+                    } else {
+                        Some(bake)
+                    }
+                }),
+                LoadState::Failed(_) => None,
+                LoadState::NotLoaded | LoadState::Loading => continue,
+            }
+        } else if packed_bake_exists(&input.name) {
+            ecmds.insert(CascadeBakeHandle(
+                asset_server.load(cascade_bake_path(&input.name)),
+            ));
+            continue;
+        } else {
+            None
+        };
+
+        if let Some(bake) = bake {
+            ecmds.insert((bake.uniform(), bake.view_uniform()));
+        } else {
+            debug!(
+                "No packed bake for cascade {}, using png probe textures",
+                input.name
+            );
+            ecmds.insert((
+                input.into_uniform(&asset_server),
+                input.into_view_uniform(&asset_server),
+            ));
+        }
     }
 }
 
+/// Loading a missing file logs an error, so only packed bakes that exist are requested. The web
+/// build can't check and always uses the png pair.
+fn packed_bake_exists(name: &str) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::path::Path::new("assets")
+            .join(cascade_bake_path(name))
+            .is_file()
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = name;
+        false
+    }
+}
+
+/// Scale applied to the decoded probe texels in `sample_cascade_stochastic`, so that
+/// `irradiance * diffuse_color` matches the shader.
+pub const PROBE_IRRADIANCE_SCALE: f32 = 0.5 * PI * 1000.0 * 5.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProbeSample {
+    pub irradiance: Vec3,
+    /// Directional light visibility baked into the probes.
+    pub dir_shadow: f32,
+}
+
+impl ProbeSample {
+    pub fn luminance(&self) -> f32 {
+        self.irradiance.dot(vec3(0.2126, 0.7152, 0.0722))
+    }
+}
+
+/// CPU side probe lookup, for gameplay that needs to know how lit something is.
+#[derive(SystemParam)]
+pub struct CascadeSampler<'w> {
+    cascade_bvh: Res<'w, CascadeBvh>,
+    images: Res<'w, Assets<Image>>,
+}
+
+impl CascadeSampler<'_> {
+    /// Irradiance at `position` facing `normal`, selecting and blending cascades the same way
+    /// draws do. None if there are no cascades or their textures aren't loaded.
+    pub fn sample(&self, position: Vec3, normal: Vec3) -> Option<ProbeSample> {
+        let p = Vec3A::from(position);
+        let selection = self.cascade_bvh.select(
+            obvhs::aabb::Aabb::new(p - 0.01, p + 0.01),
+            &mut DebugLines::default(),
+        );
+        let primary = self.sample_cascade(selection.primary, position, normal)?;
+        if selection.blend > 0.0
+            && let Some(secondary) = self.sample_cascade(selection.secondary, position, normal)
+        {
+            return Some(ProbeSample {
+                irradiance: primary
+                    .irradiance
+                    .lerp(secondary.irradiance, selection.blend),
+                dir_shadow: primary.dir_shadow
+                    + (secondary.dir_shadow - primary.dir_shadow) * selection.blend,
+            });
+        }
+        Some(primary)
+    }
+
+    fn sample_cascade(&self, index: u32, position: Vec3, normal: Vec3) -> Option<ProbeSample> {
+        let cascade = self.cascade_bvh.uniforms.get(index as usize)?;
+        if cascade.fallback_ambient.w > 0.5 {
+            return Some(ProbeSample {
+                irradiance: cascade.fallback_ambient.truncate(),
+                dir_shadow: 1.0,
+            });
+        }
+        let gi = self.images.get(&cascade.probes_gi)?;
+        let id = self.images.get(&cascade.probes_id)?;
+        sample_cascade_irradiance(cascade, gi, id, position, normal)
+    }
+}
+
+/// Trilinear probe lookup, the deterministic version of `sample_cascade_stochastic`.
+/// None if the images are not uncompressed 8 bit rgba with their data on the CPU.
+pub fn sample_cascade_irradiance(
+    cascade: &CascadeUniform,
+    probes_gi: &Image,
+    probes_id: &Image,
+    ws_position: Vec3,
+    ws_normal: Vec3,
+) -> Option<ProbeSample> {
+    let cs_position = cascade.cascade_from_world.transform_point3(ws_position);
+    let ls_position = (cs_position - cascade.cascade_position) / cascade.cascade_spacing;
+    let base = ls_position
+        .floor()
+        .clamp(Vec3::ZERO, (cascade.cascade_res - 2.0).max(Vec3::ZERO));
+    let alpha = (ls_position - base).clamp(Vec3::ZERO, Vec3::ONE);
+    let oct = oct_encode(ws_normal.normalize_or(Vec3::Y)) * 0.5 + 0.5;
+    let probe_pad_size = cascade.probe_size + 2.0;
+
+    let mut sum_irradiance = Vec3::ZERO;
+    let mut sum_dir_shadow = 0.0;
+    let mut sum_weight = 0.0;
+    for i in 0..8 {
+        let offset = vec3((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32);
+        let trilinear = (Vec3::ONE - alpha).lerp(alpha, offset);
+        let weight = trilinear.x * trilinear.y * trilinear.z;
+        if weight <= 0.0 {
+            continue;
+        }
+        let probe_pos = base + offset;
+        let id_xy = uvec2(
+            probe_pos.x as u32,
+            (probe_pos.y + probe_pos.z * cascade.cascade_res.y) as u32,
+        );
+        let probe_id_info = fetch_rgba8(probes_id, id_xy)?;
+        let probe_xy_id = (vec2(probe_id_info.x, probe_id_info.y) * 255.0 + 0.5).floor();
+        let texel = 1.0 + oct * cascade.probe_size + probe_pad_size * probe_xy_id;
+        let rgbe = bilinear_rgba8(probes_gi, texel * cascade.gi_texel)?;
+        sum_irradiance += weight * rgbe_to_rgb(rgbe);
+        sum_dir_shadow += weight * probe_id_info.z;
+        sum_weight += weight;
+    }
+    if sum_weight <= 0.0 {
+        return None;
+    }
+    Some(ProbeSample {
+        irradiance: sum_irradiance / sum_weight * PROBE_IRRADIANCE_SCALE,
+        dir_shadow: sum_dir_shadow / sum_weight,
+    })
+}
+
+/// Matches `rgbe2rgb` in the shader includes.
+pub fn rgbe_to_rgb(rgbe: Vec4) -> Vec3 {
+    rgbe.truncate() * (rgbe.w * 255.0 - 128.0).exp2()
+}
+
+/// Matches `octEncode` in std_mat.frag.
+pub fn oct_encode(v: Vec3) -> Vec2 {
+    let l1norm = v.x.abs() + v.y.abs() + v.z.abs();
+    let result = vec2(v.x, v.y) / l1norm;
+    if v.z < 0.0 {
+        (1.0 - vec2(result.y, result.x).abs())
+            * vec2(sign_not_zero(result.x), sign_not_zero(result.y))
+    } else {
+        result
+    }
+}
+
+/// Inverse of `oct_encode`.
+pub fn oct_decode(e: Vec2) -> Vec3 {
+    let mut v = vec3(e.x, e.y, 1.0 - e.x.abs() - e.y.abs());
+    if v.z < 0.0 {
+        let xy = (1.0 - vec2(v.y, v.x).abs()) * vec2(sign_not_zero(v.x), sign_not_zero(v.y));
+        v.x = xy.x;
+        v.y = xy.y;
+    }
+    v.normalize()
+}
+
+fn sign_not_zero(k: f32) -> f32 {
+    if k >= 0.0 { 1.0 } else { -1.0 }
+}
+
+fn fetch_rgba8(image: &Image, xy: UVec2) -> Option<Vec4> {
+    if !matches!(
+        image.texture_descriptor.format,
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb
+    ) {
+        return None;
+    }
+    let xy = xy.min(uvec2(image.width() - 1, image.height() - 1));
+    let p = image.pixel_bytes(xy.extend(0))?;
+    Some(vec4(p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32) / 255.0)
+}
+
+/// Bilinear filtered fetch with clamp to edge, like the linear sampler used for probes_gi.
+fn bilinear_rgba8(image: &Image, uv: Vec2) -> Option<Vec4> {
+    let size = vec2(image.width() as f32, image.height() as f32);
+    let p = (uv * size - 0.5).max(Vec2::ZERO);
+    let p0 = p.floor();
+    let f = p - p0;
+    let p0 = p0.as_uvec2();
+    let a = fetch_rgba8(image, p0)?;
+    let b = fetch_rgba8(image, p0 + uvec2(1, 0))?;
+    let c = fetch_rgba8(image, p0 + uvec2(0, 1))?;
+    let d = fetch_rgba8(image, p0 + uvec2(1, 1))?;
+    Some(a.lerp(b, f.x).lerp(c.lerp(d, f.x), f.y))
+}
+
 pub fn sampler_nearest_clamp() -> ImageSampler {
     ImageSampler::Descriptor(ImageSamplerDescriptor {
         mag_filter: ImageFilterMode::Nearest,
@@ -281,3 +1058,141 @@ pub fn sampler_linear_clamp() -> ImageSampler {
         ..Default::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::RenderAssetUsages;
+    use wgpu_types::{Extent3d, TextureDimension};
+
+    use super::*;
+
+    fn rgba8_image(width: u32, height: u32, data: Vec<u8>) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    #[test]
+    fn oct_round_trip() {
+        let normals = [
+            Vec3::X,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+            vec3(1.0, 2.0, 3.0),
+            vec3(-0.3, 0.5, -0.8),
+            vec3(0.7, -0.1, -0.2),
+            vec3(-1.0, -1.0, -1.0),
+        ];
+        for n in normals.map(Vec3::normalize) {
+            let e = oct_encode(n);
+            assert!(e.abs().max_element() <= 1.0, "{n} encoded to {e}");
+            let decoded = oct_decode(e);
+            assert!(decoded.dot(n) > 0.99999, "{n} decoded to {decoded}");
+        }
+    }
+
+    #[cfg(feature = "asset_baking")]
+    #[test]
+    fn rgbe_round_trip() {
+        use crate::probe_baker::rgb_to_rgbe;
+
+        assert_eq!(rgb_to_rgbe(Vec3::ZERO), [0; 4]);
+        for rgb in [
+            vec3(1.0, 0.5, 0.25),
+            vec3(0.001, 0.002, 0.0005),
+            vec3(300.0, 20.0, 1.0),
+            vec3(0.0, 0.0, 7.5),
+        ] {
+            let rgbe = rgb_to_rgbe(rgb);
+            let decoded = rgbe_to_rgb(Vec4::from_array(rgbe.map(|b| b as f32 / 255.0)));
+            // 8 bits of mantissa for the largest channel.
+            let tolerance = rgb.max_element() / 128.0;
+            assert!(
+                (decoded - rgb).abs().max_element() <= tolerance,
+                "{rgb} decoded to {decoded}"
+            );
+        }
+    }
+
+    /// 2x2x2 probes, each tile filled with one color so the lookup is independent of the normal.
+    #[test]
+    fn lookup_synthetic_probes() {
+        const PROBE_SIZE: u32 = 6;
+        const PAD_SIZE: u32 = PROBE_SIZE + 2;
+        let res = UVec3::splat(2);
+        let probe_count = res.x * res.y * res.z;
+
+        // Probe i is at tile (i, 0) of the atlas and is lit by mantissa 10 * (i + 1), exponent 0.
+        let (gi_width, gi_height) = (PAD_SIZE * probe_count, PAD_SIZE);
+        let mut gi = Vec::new();
+        for _ in 0..gi_height {
+            for x in 0..gi_width {
+                let i = (x / PAD_SIZE) as u8;
+                gi.extend_from_slice(&[10 * (i + 1), 0, 0, 128]);
+            }
+        }
+        // Rows are y + z * res.y, rg is the tile, b the directional shadow.
+        let mut id = Vec::new();
+        for row in 0..res.y * res.z {
+            for x in 0..res.x {
+                let i = (x + row * res.x) as u8;
+                id.extend_from_slice(&[i, 0, 255, 255]);
+            }
+        }
+        let probes_gi = rgba8_image(gi_width, gi_height, gi);
+        let probes_id = rgba8_image(res.x, res.y * res.z, id);
+
+        let cascade = CascadeUniform {
+            probes_gi: Handle::default(),
+            probes_id: Handle::default(),
+            cascade_position: vec3(-1.0, 0.0, 0.0),
+            cascade_res: res.as_vec3(),
+            cascade_spacing: Vec3::splat(2.0),
+            probe_size: PROBE_SIZE as f32,
+            gi_texel: 1.0 / gi_width as f32,
+            id_texel: 1.0 / vec2(res.x as f32, (res.y * res.z) as f32),
+            fallback_ambient: Vec4::ZERO,
+            cascade_from_world: Mat4::IDENTITY,
+        };
+        let probe_irradiance = |i: u32| (10 * (i + 1)) as f32 / 255.0 * PROBE_IRRADIANCE_SCALE;
+        let sample = |position: Vec3, normal: Vec3| {
+            sample_cascade_irradiance(&cascade, &probes_gi, &probes_id, position, normal).unwrap()
+        };
+
+        // On a probe only that probe contributes, whatever the normal.
+        for (position, i) in [
+            (vec3(-1.0, 0.0, 0.0), 0),
+            (vec3(1.0, 0.0, 0.0), 1),
+            (vec3(-1.0, 2.0, 0.0), 2),
+            (vec3(1.0, 2.0, 2.0), 7),
+        ] {
+            for normal in [Vec3::Y, Vec3::NEG_Z, vec3(0.3, -0.4, 0.5)] {
+                let s = sample(position, normal);
+                assert!(
+                    (s.irradiance.x - probe_irradiance(i)).abs() < 1e-3,
+                    "probe {i} at {position}: {s:?}"
+                );
+                assert_eq!(s.irradiance.yz(), Vec2::ZERO);
+                assert_eq!(s.dir_shadow, 1.0);
+            }
+        }
+
+        // The center is the average of all eight.
+        let expected = (0..probe_count).map(probe_irradiance).sum::<f32>() / probe_count as f32;
+        let s = sample(vec3(0.0, 1.0, 1.0), Vec3::X);
+        assert!((s.irradiance.x - expected).abs() < 1e-2, "{s:?}");
+
+        // Positions outside the grid clamp to the nearest probe.
+        let s = sample(vec3(-5.0, -5.0, -5.0), Vec3::Y);
+        assert!((s.irradiance.x - probe_irradiance(0)).abs() < 1e-3, "{s:?}");
+    }
+}
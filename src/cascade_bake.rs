@@ -0,0 +1,301 @@
+//! Packed single file probe bake format. Holds both probe textures along with the metadata needed
+//! to build a `CascadeUniform`, so a bake can't be used with the wrong cascade layout.
+//!
+//! Layout, all little endian:
+//! ```text
+//! magic        [u8; 8]  b"BJ7PROBE"
+//! version      u32
+//! aabb_min     [f32; 3]
+//! aabb_max     [f32; 3]
+//! resolution   [f32; 3]  probe spacing
+//! rotation     [f32; 4]  world_from_cascade quaternion, xyzw
+//! cascade_res  [u32; 3]  probe count
+//! probe_size   f32       before padding
+//! gi_texel     f32
+//! gi_len       u32
+//! gi           [u8; gi_len] png
+//! id_len       u32
+//! id           [u8; id_len] png
+//! ```
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, RenderAssetUsages, io::Reader},
+    image::{CompressedImageFormats, ImageType},
+    prelude::*,
+};
+
+use crate::cascade::{
+    CascadeInput, CascadeUniform, CascadeViewUniform, sampler_linear_clamp, sampler_nearest_clamp,
+};
+
+pub const CASCADE_BAKE_MAGIC: &[u8; 8] = b"BJ7PROBE";
+pub const CASCADE_BAKE_VERSION: u32 = 2;
+pub const CASCADE_BAKE_EXTENSION: &str = "probes";
+
+pub fn cascade_bake_path(name: &str) -> String {
+    format!("bake/probes_{name}.{CASCADE_BAKE_EXTENSION}")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CascadeBakeHeader {
+    pub ws_aabb: obvhs::aabb::Aabb,
+    pub resolution: Vec3A,
+    pub rotation: Quat,
+    pub cascade_res: UVec3,
+    pub probe_size: f32,
+    pub gi_texel: f32,
+}
+
+impl CascadeBakeHeader {
+    pub fn from_input(input: &CascadeInput, probe_size: f32, gi_texel: f32) -> Self {
+        CascadeBakeHeader {
+            ws_aabb: input.ws_aabb,
+            resolution: input.resolution,
+            rotation: input.rotation,
+            cascade_res: (input.ws_aabb.diagonal() / input.resolution)
+                .ceil()
+                .as_uvec3(),
+            probe_size,
+            gi_texel,
+        }
+    }
+
+    /// Checks the bake was made for this cascade layout.
+    pub fn matches(&self, input: &CascadeInput) -> Result<(), CascadeBakeError> {
+        let expected = CascadeBakeHeader::from_input(input, self.probe_size, self.gi_texel);
+        let eps = 1e-3;
+        if !self.ws_aabb.min.abs_diff_eq(expected.ws_aabb.min, eps)
+            || !self.ws_aabb.max.abs_diff_eq(expected.ws_aabb.max, eps)
+        {
+            return Err(CascadeBakeError::Mismatch("aabb"));
+        }
+        if !self.resolution.abs_diff_eq(expected.resolution, eps) {
+            return Err(CascadeBakeError::Mismatch("resolution"));
+        }
+        // q and -q are the same rotation
+        if !self.rotation.abs_diff_eq(expected.rotation, eps)
+            && !self.rotation.abs_diff_eq(-expected.rotation, eps)
+        {
+            return Err(CascadeBakeError::Mismatch("rotation"));
+        }
+        if self.cascade_res != expected.cascade_res {
+            return Err(CascadeBakeError::Mismatch("cascade_res"));
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, gi_png: &[u8], id_png: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(80 + gi_png.len() + id_png.len());
+        out.extend_from_slice(CASCADE_BAKE_MAGIC);
+        out.extend_from_slice(&CASCADE_BAKE_VERSION.to_le_bytes());
+        for v in [self.ws_aabb.min, self.ws_aabb.max, self.resolution] {
+            for c in v.to_array() {
+                out.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        for c in self.rotation.to_array() {
+            out.extend_from_slice(&c.to_le_bytes());
+        }
+        for c in self.cascade_res.to_array() {
+            out.extend_from_slice(&c.to_le_bytes());
+        }
+        out.extend_from_slice(&self.probe_size.to_le_bytes());
+        out.extend_from_slice(&self.gi_texel.to_le_bytes());
+        for png in [gi_png, id_png] {
+            out.extend_from_slice(&(png.len() as u32).to_le_bytes());
+            out.extend_from_slice(png);
+        }
+        out
+    }
+
+    /// Returns the header and the gi and id png bytes.
+    pub fn read(bytes: &[u8]) -> Result<(Self, &[u8], &[u8]), CascadeBakeError> {
+        let mut r = ByteReader { bytes, offset: 0 };
+        if r.take(8)? != CASCADE_BAKE_MAGIC {
+            return Err(CascadeBakeError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != CASCADE_BAKE_VERSION {
+            return Err(CascadeBakeError::UnsupportedVersion(version));
+        }
+        let min = r.vec3a()?;
+        let max = r.vec3a()?;
+        let resolution = r.vec3a()?;
+        let rotation = Quat::from_xyzw(r.f32()?, r.f32()?, r.f32()?, r.f32()?);
+        let cascade_res = uvec3(r.u32()?, r.u32()?, r.u32()?);
+        let probe_size = r.f32()?;
+        let gi_texel = r.f32()?;
+        let gi_len = r.u32()? as usize;
+        let gi_png = r.take(gi_len)?;
+        let id_len = r.u32()? as usize;
+        let id_png = r.take(id_len)?;
+        let header = CascadeBakeHeader {
+            ws_aabb: obvhs::aabb::Aabb::new(min, max),
+            resolution,
+            rotation,
+            cascade_res,
+            probe_size,
+            gi_texel,
+        };
+        Ok((header, gi_png, id_png))
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CascadeBakeError> {
+        let end = self.offset + len;
+        let slice = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(CascadeBakeError::Truncated)?;
+        self.offset = end;
+        Ok(slice)
+    }
+    fn u32(&mut self) -> Result<u32, CascadeBakeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn f32(&mut self) -> Result<f32, CascadeBakeError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn vec3a(&mut self) -> Result<Vec3A, CascadeBakeError> {
+        Ok(vec3a(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+#[derive(Debug)]
+pub enum CascadeBakeError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    Image(String),
+    /// The bake was made for a different cascade layout.
+    Mismatch(&'static str),
+}
+
+impl std::fmt::Display for CascadeBakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CascadeBakeError::Io(e) => write!(f, "io error: {e}"),
+            CascadeBakeError::BadMagic => write!(f, "not a probe bake file"),
+            CascadeBakeError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported version {v}, expected {CASCADE_BAKE_VERSION}"
+                )
+            }
+            CascadeBakeError::Truncated => write!(f, "file is truncated"),
+            CascadeBakeError::Image(e) => write!(f, "image error: {e}"),
+            CascadeBakeError::Mismatch(field) => {
+                write!(f, "bake {field} does not match the cascade")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CascadeBakeError {}
+
+impl From<std::io::Error> for CascadeBakeError {
+    fn from(e: std::io::Error) -> Self {
+        CascadeBakeError::Io(e)
+    }
+}
+
+#[derive(Asset, TypePath, Clone)]
+pub struct CascadeBake {
+    pub header: CascadeBakeHeader,
+    pub probes_gi: Handle<Image>,
+    pub probes_id: Handle<Image>,
+}
+
+impl CascadeBake {
+    pub fn uniform(&self) -> CascadeUniform {
+        let h = &self.header;
+        let cascade_res = h.cascade_res.as_vec3();
+        CascadeUniform {
+            probes_gi: self.probes_gi.clone(),
+            probes_id: self.probes_id.clone(),
+            cascade_position: h.ws_aabb.min.into(),
+            cascade_res,
+            cascade_spacing: h.resolution.into(),
+            probe_size: h.probe_size,
+            gi_texel: h.gi_texel,
+            id_texel: vec2(1.0 / cascade_res.x, 1.0 / (cascade_res.y * cascade_res.z)),
+            fallback_ambient: Vec4::ZERO,
+            cascade_from_world: Mat4::from_quat(h.rotation.inverse()),
+        }
+    }
+
+    // Note: if making changes copy uniform() and rename to CascadeViewUniform
+    pub fn view_uniform(&self) -> CascadeViewUniform {
+        let h = &self.header;
+        let cascade_res = h.cascade_res.as_vec3();
+        CascadeViewUniform {
+            probes_gi: self.probes_gi.clone(),
+            probes_id: self.probes_id.clone(),
+            cascade_position: h.ws_aabb.min.into(),
+            cascade_res,
+            cascade_spacing: h.resolution.into(),
+            probe_size: h.probe_size,
+            gi_texel: h.gi_texel,
+            id_texel: vec2(1.0 / cascade_res.x, 1.0 / (cascade_res.y * cascade_res.z)),
+            fallback_ambient: Vec4::ZERO,
+            cascade_from_world: Mat4::from_quat(h.rotation.inverse()),
+        }
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct CascadeBakeLoader;
+
+impl AssetLoader for CascadeBakeLoader {
+    type Asset = CascadeBake;
+    type Settings = ();
+    type Error = CascadeBakeError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<CascadeBake, CascadeBakeError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let (header, gi_png, id_png) = CascadeBakeHeader::read(&bytes)?;
+
+        let load_png = |png: &[u8], sampler| {
+            Image::from_buffer(
+                png,
+                ImageType::Extension("png"),
+                CompressedImageFormats::NONE,
+                false,
+                sampler,
+                RenderAssetUsages::default(),
+            )
+            .map_err(|e| CascadeBakeError::Image(e.to_string()))
+        };
+        let probes_gi = load_context.add_labeled_asset(
+            String::from("gi"),
+            load_png(gi_png, sampler_linear_clamp())?,
+        );
+        let probes_id = load_context.add_labeled_asset(
+            String::from("id"),
+            load_png(id_png, sampler_nearest_clamp())?,
+        );
+
+        Ok(CascadeBake {
+            header,
+            probes_gi,
+            probes_id,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[CASCADE_BAKE_EXTENSION]
+    }
+}
@@ -0,0 +1,164 @@
+use bevy::prelude::*;
+use bgl2::render::RenderSet;
+
+use crate::{
+    cascade::{
+        CascadeBvh, CascadeSelection, cascade_local_aabb, enlarged_cascade_local_aabb,
+        sample_cascade_irradiance, world_from_cascade,
+    },
+    draw_debug::DebugLines,
+};
+
+/// Dev overlay for cascades. Press F4 to cycle through the modes.
+#[derive(Resource, Default)]
+pub struct CascadeDebugPlugin;
+
+impl Plugin for CascadeDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CascadeDebugSettings>().add_systems(
+            PostUpdate,
+            (cycle_cascade_debug_mode, draw_cascade_debug)
+                .chain()
+                .in_set(RenderSet::Prepare),
+        );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct CascadeDebugSettings {
+    pub mode: CascadeDebugMode,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum CascadeDebugMode {
+    #[default]
+    Off,
+    /// Each cascade's AABB and its enlarged influence region.
+    Volumes,
+    /// Volumes plus the probe lattice, colored by sampled GI.
+    Probes,
+    /// Tint each mesh by the cascade it was assigned.
+    MeshAssignment,
+}
+
+impl CascadeDebugMode {
+    fn next(self) -> Self {
+        match self {
+            CascadeDebugMode::Off => CascadeDebugMode::Volumes,
+            CascadeDebugMode::Volumes => CascadeDebugMode::Probes,
+            CascadeDebugMode::Probes => CascadeDebugMode::MeshAssignment,
+            CascadeDebugMode::MeshAssignment => CascadeDebugMode::Off,
+        }
+    }
+}
+
+/// Probe lattices larger than this are skipped, they would just be a wall of lines.
+const MAX_DEBUG_PROBES: usize = 32768;
+
+/// Distinct color per cascade index.
+pub fn cascade_debug_color(index: u32) -> Vec3 {
+    let hue = (index as f32 * 0.618034).fract() * 360.0;
+    Color::hsl(hue, 0.9, 0.5).to_linear().to_vec3()
+}
+
+pub fn cascade_selection_debug_color(selection: CascadeSelection) -> Vec3 {
+    cascade_debug_color(selection.primary)
+        .lerp(cascade_debug_color(selection.secondary), selection.blend)
+}
+
+fn cycle_cascade_debug_mode(
+    key: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<CascadeDebugSettings>,
+) {
+    if key.just_pressed(KeyCode::F4) {
+        settings.mode = settings.mode.next();
+        info!("Cascade debug mode: {:?}", settings.mode);
+    }
+}
+
+fn draw_cascade_debug(
+    settings: Res<CascadeDebugSettings>,
+    cascade_bvh: Res<CascadeBvh>,
+    images: Res<Assets<Image>>,
+    mut debug: ResMut<DebugLines>,
+) {
+    if !matches!(
+        settings.mode,
+        CascadeDebugMode::Volumes | CascadeDebugMode::Probes
+    ) {
+        return;
+    }
+    for (i, cascade) in cascade_bvh.uniforms.iter().enumerate() {
+        let color = cascade_debug_color(i as u32);
+        let world_from_cascade = world_from_cascade(cascade);
+        draw_oriented_aabb(
+            &mut debug,
+            cascade_local_aabb(cascade),
+            world_from_cascade,
+            color,
+        );
+        draw_oriented_aabb(
+            &mut debug,
+            enlarged_cascade_local_aabb(cascade),
+            world_from_cascade,
+            color * 0.35,
+        );
+
+        if settings.mode != CascadeDebugMode::Probes {
+            continue;
+        }
+        let res = cascade.cascade_res.as_uvec3();
+        if (res.x * res.y * res.z) as usize > MAX_DEBUG_PROBES {
+            warn_once!("Cascade {i} has too many probes to draw");
+            continue;
+        }
+        let gi = images.get(&cascade.probes_gi);
+        let id = images.get(&cascade.probes_id);
+        let half = cascade.cascade_spacing.min_element() * 0.1;
+        for z in 0..res.z {
+            for y in 0..res.y {
+                for x in 0..res.x {
+                    let p = world_from_cascade.transform_point3(
+                        cascade.cascade_position
+                            + vec3(x as f32, y as f32, z as f32) * cascade.cascade_spacing,
+                    );
+                    let probe_color = match (gi, id) {
+                        (Some(gi), Some(id)) => {
+                            sample_cascade_irradiance(cascade, gi, id, p, Vec3::Y)
+                                .map(|s| s.irradiance / (s.irradiance + 1000.0))
+                                .unwrap_or(color)
+                        }
+                        _ => color,
+                    };
+                    debug.line(p - Vec3::X * half, p + Vec3::X * half, probe_color);
+                    debug.line(p - Vec3::Y * half, p + Vec3::Y * half, probe_color);
+                    debug.line(p - Vec3::Z * half, p + Vec3::Z * half, probe_color);
+                }
+            }
+        }
+    }
+}
+
+fn draw_oriented_aabb(
+    debug: &mut DebugLines,
+    aabb: obvhs::aabb::Aabb,
+    world_from_local: Mat4,
+    color: Vec3,
+) {
+    let corner = |i: u32| {
+        let min = Vec3::from(aabb.min);
+        let max = Vec3::from(aabb.max);
+        world_from_local.transform_point3(vec3(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        ))
+    };
+    for i in 0..8 {
+        for axis in [1, 2, 4] {
+            if i & axis == 0 {
+                debug.line(corner(i), corner(i | axis), color);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Cascades listed in a `.cascades.json` file, for scenes that don't come from Blender with
+//! `BAKE` nodes.
+//!
+//! ```json
+//! {
+//!     "cascades": [
+//!         { "name": "nave", "min": [-47.5, 0.1, -25.5], "max": [24.5, 112.1, 42.5], "resolution": [1.5, 1.5, 1.5] }
+//!     ]
+//! }
+//! ```
+//!
+//! An optional `"rotation": [x, y, z, w]` quaternion orients the cascade, `min` and `max` are then
+//! in cascade space, see `CascadeInput`.
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+};
+use serde::Deserialize;
+
+use crate::cascade::{CascadeInput, SceneBakeName};
+
+pub const CASCADE_MANIFEST_EXTENSION: &str = "cascades.json";
+
+#[derive(Resource, Default)]
+pub struct CascadeManifestPlugin;
+
+impl Plugin for CascadeManifestPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<CascadeManifest>()
+            .init_asset_loader::<CascadeManifestLoader>()
+            .add_systems(Update, spawn_manifest_cascades);
+
+        #[cfg(feature = "dev")]
+        app.add_systems(
+            Update,
+            reload_manifest_cascades.before(spawn_manifest_cascades),
+        );
+    }
+}
+
+#[derive(Asset, TypePath, Deserialize, Clone, Debug)]
+pub struct CascadeManifest {
+    pub cascades: Vec<CascadeManifestEntry>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CascadeManifestEntry {
+    pub name: String,
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    /// Probe spacing.
+    pub resolution: [f32; 3],
+    #[serde(default)]
+    pub rotation: Option<[f32; 4]>,
+}
+
+impl CascadeManifestEntry {
+    pub fn cascade_input(&self, name_prefix: &str) -> CascadeInput {
+        CascadeInput {
+            name: format!("{name_prefix}{}", self.name),
+            ws_aabb: obvhs::aabb::Aabb::new(self.min.into(), self.max.into()),
+            resolution: self.resolution.into(),
+            rotation: self.rotation.map(Quat::from_array).unwrap_or_default(),
+        }
+    }
+}
+
+/// Spawns the manifest's cascades as children of this entity once it loads. Names are prefixed
+/// with the entity's `SceneBakeName` if it has one, same as `blender_cascades`.
+#[derive(Component)]
+pub struct SceneCascadeManifest(pub Handle<CascadeManifest>);
+
+#[derive(Component)]
+struct ManifestCascadesSpawned;
+
+#[derive(Component)]
+struct ManifestCascade;
+
+/// Path of the manifest that sits next to a scene file, `foo.gltf` -> `foo.cascades.json`.
+pub fn sibling_manifest_path(scene_path: &std::path::Path) -> std::path::PathBuf {
+    scene_path.with_extension(CASCADE_MANIFEST_EXTENSION)
+}
+
+fn spawn_manifest_cascades(
+    mut commands: Commands,
+    scenes: Query<
+        (Entity, &SceneCascadeManifest, Option<&SceneBakeName>),
+        Without<ManifestCascadesSpawned>,
+    >,
+    manifests: Res<Assets<CascadeManifest>>,
+) {
+    for (entity, manifest, bake_name) in &scenes {
+        let Some(manifest) = manifests.get(&manifest.0) else {
+            continue;
+        };
+        let name_prefix = bake_name.map(|n| n.0.as_str()).unwrap_or_default();
+        commands
+            .entity(entity)
+            .insert(ManifestCascadesSpawned)
+            .with_children(|parent| {
+                for entry in &manifest.cascades {
+                    parent.spawn((entry.cascade_input(name_prefix), ManifestCascade));
+                }
+            });
+    }
+}
+
+#[cfg(feature = "dev")]
+fn reload_manifest_cascades(
+    mut commands: Commands,
+    mut manifest_events: MessageReader<AssetEvent<CascadeManifest>>,
+    scenes: Query<(Entity, &SceneCascadeManifest, &Children), With<ManifestCascadesSpawned>>,
+    manifest_cascades: Query<(), With<ManifestCascade>>,
+) {
+    for event in manifest_events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        for (entity, manifest, children) in &scenes {
+            if manifest.0.id() != *id {
+                continue;
+            }
+            info!("Cascade manifest changed, respawning cascades");
+            for child in children.iter() {
+                if manifest_cascades.contains(child) {
+                    commands.entity(child).despawn();
+                }
+            }
+            commands.entity(entity).remove::<ManifestCascadesSpawned>();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CascadeManifestError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for CascadeManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CascadeManifestError::Io(e) => write!(f, "io error: {e}"),
+            CascadeManifestError::Json(e) => write!(f, "invalid cascade manifest: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CascadeManifestError {}
+
+impl From<std::io::Error> for CascadeManifestError {
+    fn from(e: std::io::Error) -> Self {
+        CascadeManifestError::Io(e)
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct CascadeManifestLoader;
+
+impl AssetLoader for CascadeManifestLoader {
+    type Asset = CascadeManifest;
+    type Settings = ();
+    type Error = CascadeManifestError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<CascadeManifest, CascadeManifestError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        serde_json::from_slice(&bytes).map_err(CascadeManifestError::Json)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[CASCADE_MANIFEST_EXTENSION]
+    }
+}
@@ -15,8 +15,12 @@ pub struct DrawDebugPlugin;
 
 impl Plugin for DrawDebugPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<DebugLines>()
-            .add_systems(PostUpdate, draw_debug_lines.in_set(RenderSet::RenderDebug));
+        app.init_resource::<DebugLines>().add_systems(
+            PostUpdate,
+            draw_debug_lines
+                .in_set(RenderSet::RenderDebug)
+                .after(crate::post_process::render_post_process),
+        );
     }
 }
 
@@ -0,0 +1,159 @@
+//! Local fog in addition to the global `Fog`. Entities with a `FogVolume` are gathered each frame
+//! and the nearest ones in view are applied per fragment in std_mat.frag, along the view ray.
+
+use bevy::{
+    camera::primitives::{Frustum, Sphere},
+    prelude::*,
+};
+use bgl2::render::RenderSet;
+use uniform_set_derive::UniformSet;
+
+// Every volume is intersected by every fragment, keep this small.
+pub const DEFAULT_MAX_FOG_VOLUMES: usize = 4;
+pub const DEFAULT_MAX_FOG_VOLUMES_DEF: (&str, &str) = ("MAX_FOG_VOLUMES", "4");
+
+#[derive(Resource, Default)]
+pub struct FogVolumePlugin;
+
+impl Plugin for FogVolumePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FogVolumeUniforms>()
+            .add_systems(Update, gather_fog_volumes.in_set(RenderSet::Prepare));
+    }
+}
+
+/// Sized by `shape`, placed, rotated and scaled by the entity's `GlobalTransform`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct FogVolume {
+    pub shape: FogVolumeShape,
+    /// Extinction per meter.
+    pub density: f32,
+    /// Linear, lit by the cascades and lights like the global fog.
+    pub color: Vec3,
+    /// Fraction of the volume, from the surface inwards, over which the density fades in.
+    pub falloff: f32,
+    /// Adds the scene's caustic pattern, tinted by `Fog::caustics`, so only does something in
+    /// scenes that have caustics.
+    pub caustics: bool,
+}
+
+impl Default for FogVolume {
+    fn default() -> Self {
+        FogVolume {
+            shape: FogVolumeShape::Box {
+                half_size: Vec3::splat(0.5),
+            },
+            density: 0.1,
+            color: Vec3::ONE,
+            falloff: 0.2,
+            caustics: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FogVolumeShape {
+    Box { half_size: Vec3 },
+    Sphere { radius: f32 },
+}
+
+impl FogVolumeShape {
+    fn size(&self) -> Vec3 {
+        match *self {
+            FogVolumeShape::Box { half_size } => half_size,
+            FogVolumeShape::Sphere { radius } => Vec3::splat(radius),
+        }
+    }
+}
+
+#[derive(UniformSet, Resource, Clone, Default)]
+#[uniform_set(prefix = "ub_")]
+pub struct FogVolumeUniforms {
+    /// Rows of the affine transform from world space to the volume's unit space, where the box
+    /// spans -1..1 and the sphere has radius 1. Lengths along a ray are kept in world units.
+    #[array_max("MAX_FOG_VOLUMES")]
+    pub fog_volume_local_x: Vec<Vec4>,
+    #[array_max("MAX_FOG_VOLUMES")]
+    pub fog_volume_local_y: Vec<Vec4>,
+    #[array_max("MAX_FOG_VOLUMES")]
+    pub fog_volume_local_z: Vec<Vec4>,
+    /// rgb color, a density.
+    #[array_max("MAX_FOG_VOLUMES")]
+    pub fog_volume_color_density: Vec<Vec4>,
+    /// x 1.0 for spheres, y falloff, z 1.0 with caustics.
+    #[array_max("MAX_FOG_VOLUMES")]
+    pub fog_volume_params: Vec<Vec4>,
+    pub fog_volume_count: i32,
+}
+
+impl FogVolumeUniforms {
+    pub fn shader_defs(&self) -> [(&'static str, &'static str); 2] {
+        [
+            DEFAULT_MAX_FOG_VOLUMES_DEF,
+            if self.fog_volume_count > 0 {
+                ("FOG_VOLUMES", "")
+            } else {
+                ("", "")
+            },
+        ]
+    }
+}
+
+fn gather_fog_volumes(
+    volumes: Query<(&FogVolume, &GlobalTransform, Option<&InheritedVisibility>)>,
+    camera: Single<(&GlobalTransform, &Frustum), With<Camera3d>>,
+    mut uniforms: ResMut<FogVolumeUniforms>,
+) {
+    let (camera_trans, frustum) = *camera;
+    let camera_pos = camera_trans.translation();
+
+    let mut visible: Vec<(f32, &FogVolume, Affine3A)> = volumes
+        .iter()
+        .filter(|(volume, _, visibility)| {
+            volume.density > 0.0 && visibility.is_none_or(|visibility| visibility.get())
+        })
+        .filter_map(|(volume, trans, _)| {
+            let world_from_local =
+                trans.affine() * Affine3A::from_scale(volume.shape.size().max(Vec3::splat(1e-4)));
+            let center = world_from_local.translation;
+            let radius = world_from_local.matrix3.x_axis.length()
+                + world_from_local.matrix3.y_axis.length()
+                + world_from_local.matrix3.z_axis.length();
+            let sphere = Sphere { center, radius };
+            if !frustum.intersects_sphere(&sphere, true) {
+                return None;
+            }
+            // Distance to the bounding sphere, so volumes around the camera come first.
+            let distance = (Vec3::from(center).distance(camera_pos) - radius).max(0.0);
+            Some((distance, volume, world_from_local.inverse()))
+        })
+        .collect();
+    visible.sort_by(|a, b| a.0.total_cmp(&b.0));
+    visible.truncate(DEFAULT_MAX_FOG_VOLUMES);
+
+    let row = |local_from_world: &Affine3A, i: usize| {
+        let m = local_from_world.matrix3;
+        let t = local_from_world.translation;
+        vec4(m.x_axis[i], m.y_axis[i], m.z_axis[i], t[i])
+    };
+    uniforms.fog_volume_local_x = visible.iter().map(|(_, _, m)| row(m, 0)).collect();
+    uniforms.fog_volume_local_y = visible.iter().map(|(_, _, m)| row(m, 1)).collect();
+    uniforms.fog_volume_local_z = visible.iter().map(|(_, _, m)| row(m, 2)).collect();
+    uniforms.fog_volume_color_density = visible
+        .iter()
+        .map(|(_, volume, _)| volume.color.extend(volume.density))
+        .collect();
+    uniforms.fog_volume_params = visible
+        .iter()
+        .map(|(_, volume, _)| {
+            let sphere = matches!(volume.shape, FogVolumeShape::Sphere { .. });
+            vec4(
+                if sphere { 1.0 } else { 0.0 },
+                volume.falloff.clamp(0.0, 1.0),
+                if volume.caustics { 1.0 } else { 0.0 },
+                0.0,
+            )
+        })
+        .collect();
+    uniforms.fog_volume_count = visible.len() as i32;
+}
@@ -0,0 +1,418 @@
+//! Shadows for point and spot `DynamicLight`s. The lights with the most screen importance get
+//! tiles in a shared atlas, a point light uses six of them as an unrolled cube map and a spot
+//! light one. Tiles store the distance to the light over its range packed into rgba8, so this
+//! works without depth textures.
+
+use bevy::{
+    camera::primitives::{Aabb, Frustum, Sphere},
+    light::NotShadowCaster,
+    prelude::*,
+};
+use bgl2::{
+    command_encoder::CommandEncoder,
+    prepare_image::{GpuImages, TextureRef},
+    prepare_mesh::GpuMeshes,
+    render::RenderSet,
+    shader_cached,
+};
+use glow::{HasContext, PixelUnpackData};
+use uniform_set_derive::UniformSet;
+
+use crate::{
+    cascade::transform_aabb,
+    prepare_lighting::{DynamicLight, prepare_standard_lighting},
+};
+
+pub const LIGHT_SHADOW_ATLAS_SIZE: u32 = 2048;
+pub const LIGHT_SHADOW_TILE_SIZE: u32 = 512;
+pub const LIGHT_SHADOW_TILES_PER_ROW: u32 = LIGHT_SHADOW_ATLAS_SIZE / LIGHT_SHADOW_TILE_SIZE;
+pub const LIGHT_SHADOW_TILES: u32 = LIGHT_SHADOW_TILES_PER_ROW * LIGHT_SHADOW_TILES_PER_ROW;
+
+/// Cube face order, must match `sample_light_shadow` in light_shadows.glsl.
+const CUBE_FACES: [Vec3; 6] = [
+    Vec3::X,
+    Vec3::NEG_X,
+    Vec3::Y,
+    Vec3::NEG_Y,
+    Vec3::Z,
+    Vec3::NEG_Z,
+];
+
+#[derive(Resource, Default)]
+pub struct LightShadowPlugin;
+
+impl Plugin for LightShadowPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LightShadowSettings>()
+            .init_resource::<LightShadowAssignments>()
+            .insert_resource(LightShadowUniforms {
+                light_shadow_atlas: TextureRef::new(),
+                light_shadow_tiles_per_row: LIGHT_SHADOW_TILES_PER_ROW as f32,
+            })
+            .add_systems(
+                Update,
+                (assign_light_shadows, render_light_shadows)
+                    .chain()
+                    .in_set(RenderSet::Prepare)
+                    .before(prepare_standard_lighting),
+            );
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct LightShadowSettings {
+    /// At most this many lights get shadows, also limited by the atlas tiles.
+    pub max_shadowed_lights: usize,
+}
+
+impl Default for LightShadowSettings {
+    fn default() -> Self {
+        LightShadowSettings {
+            max_shadowed_lights: 4,
+        }
+    }
+}
+
+#[derive(UniformSet, Resource, Clone, Default)]
+#[uniform_set(prefix = "ub_")]
+pub struct LightShadowUniforms {
+    pub light_shadow_atlas: TextureRef,
+    pub light_shadow_tiles_per_row: f32,
+}
+
+#[derive(Clone)]
+pub struct LightShadowTile {
+    pub tile: u32,
+    pub clip_from_world: Mat4,
+}
+
+#[derive(Clone)]
+pub struct ShadowedLight {
+    pub entity: Entity,
+    pub position_range: Vec4,
+    /// x: first tile, y: tan of the spot's half angle or 0.0 for point lights.
+    pub shadow: Vec4,
+    pub tiles: Vec<LightShadowTile>,
+}
+
+/// The lights that get shadows this frame.
+#[derive(Resource, Clone, Default)]
+pub struct LightShadowAssignments(pub Vec<ShadowedLight>);
+
+impl LightShadowAssignments {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn shadow(&self, entity: Entity) -> Option<Vec4> {
+        self.0
+            .iter()
+            .find(|light| light.entity == entity)
+            .map(|light| light.shadow)
+    }
+}
+
+/// Must match `light_shadow_up` in light_shadows.glsl.
+pub fn light_shadow_up(forward: Vec3) -> Vec3 {
+    if forward.y.abs() < 0.99 {
+        Vec3::Y
+    } else {
+        Vec3::Z
+    }
+}
+
+fn tile_clip_from_world(position: Vec3, forward: Vec3, tan_half_fov: f32, range: f32) -> Mat4 {
+    let near = (range * 0.0005).max(0.05);
+    let projection = Mat4::perspective_rh_gl(2.0 * tan_half_fov.atan(), 1.0, near, range);
+    projection * Mat4::look_to_rh(position, forward, light_shadow_up(forward))
+}
+
+fn assign_light_shadows(
+    point_lights: Query<(Entity, &PointLight, &GlobalTransform), With<DynamicLight>>,
+    spot_lights: Query<(Entity, &SpotLight, &GlobalTransform), With<DynamicLight>>,
+    camera: Single<(&GlobalTransform, &Frustum), With<Camera3d>>,
+    settings: Res<LightShadowSettings>,
+    mut assignments: ResMut<LightShadowAssignments>,
+) {
+    let (camera_trans, frustum) = *camera;
+    let camera_pos = camera_trans.translation();
+
+    // Projected size of the light's range, lights whose range isn't in view don't need shadows.
+    let importance = |position: Vec3, range: f32| {
+        let sphere = Sphere {
+            center: position.into(),
+            radius: range,
+        };
+        if !frustum.intersects_sphere(&sphere, true) {
+            return 0.0;
+        }
+        range / position.distance(camera_pos).max(0.1)
+    };
+
+    struct Candidate {
+        score: f32,
+        entity: Entity,
+        position: Vec3,
+        range: f32,
+        spot: Option<(Vec3, f32)>,
+    }
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (entity, light, trans) in &point_lights {
+        if light.shadows_enabled {
+            let position = trans.translation();
+            candidates.push(Candidate {
+                score: importance(position, light.range),
+                entity,
+                position,
+                range: light.range,
+                spot: None,
+            });
+        }
+    }
+    for (entity, light, trans) in &spot_lights {
+        if light.shadows_enabled {
+            let position = trans.translation();
+            let tan_half_fov = light.outer_angle.min(80f32.to_radians()).tan();
+            candidates.push(Candidate {
+                score: importance(position, light.range),
+                entity,
+                position,
+                range: light.range,
+                spot: Some((trans.forward().as_vec3(), tan_half_fov)),
+            });
+        }
+    }
+    candidates.retain(|c| c.score > 0.0);
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.entity.cmp(&b.entity)));
+
+    assignments.0.clear();
+    let mut next_tile = 0;
+    for candidate in candidates {
+        if assignments.0.len() >= settings.max_shadowed_lights {
+            break;
+        }
+        let (faces, tan_half_fov) = match &candidate.spot {
+            Some((forward, tan_half_fov)) => (std::slice::from_ref(forward), *tan_half_fov),
+            None => (&CUBE_FACES[..], 1.0),
+        };
+        if next_tile + faces.len() as u32 > LIGHT_SHADOW_TILES {
+            continue;
+        }
+        let tiles = faces
+            .iter()
+            .enumerate()
+            .map(|(i, forward)| LightShadowTile {
+                tile: next_tile + i as u32,
+                clip_from_world: tile_clip_from_world(
+                    candidate.position,
+                    *forward,
+                    tan_half_fov,
+                    candidate.range,
+                ),
+            })
+            .collect();
+        let shadow_fov = if candidate.spot.is_some() {
+            tan_half_fov
+        } else {
+            0.0
+        };
+        assignments.0.push(ShadowedLight {
+            entity: candidate.entity,
+            position_range: candidate.position.extend(candidate.range),
+            shadow: vec4(next_tile as f32, shadow_fov, 0.0, 0.0),
+            tiles,
+        });
+        next_tile += faces.len() as u32;
+    }
+}
+
+#[derive(Resource)]
+struct LightShadowTargets {
+    framebuffer: glow::Framebuffer,
+}
+
+fn render_light_shadows(
+    casters: Query<
+        (
+            &GlobalTransform,
+            &Mesh3d,
+            &Aabb,
+            &MeshMaterial3d<StandardMaterial>,
+        ),
+        Without<NotShadowCaster>,
+    >,
+    materials: Res<Assets<StandardMaterial>>,
+    assignments: Res<LightShadowAssignments>,
+    uniforms: Res<LightShadowUniforms>,
+    bevy_window: Single<&Window>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if assignments.is_empty() {
+        return;
+    }
+
+    // Casters within each light's range.
+    let lights: Vec<(ShadowedLight, Vec<(Mat4, AssetId<Mesh>)>)> = assignments
+        .0
+        .iter()
+        .map(|light| {
+            let position = light.position_range.xyz().to_vec3a();
+            let range = light.position_range.w;
+            let draws = casters
+                .iter()
+                .filter(|(.., material)| {
+                    materials
+                        .get(*material)
+                        .is_some_and(|m| !matches!(m.alpha_mode, AlphaMode::Blend))
+                })
+                .filter_map(|(transform, mesh, aabb, _)| {
+                    let world_from_local = transform.to_matrix();
+                    let ws_aabb = transform_aabb(
+                        world_from_local,
+                        obvhs::aabb::Aabb::new(aabb.min(), aabb.max()),
+                    );
+                    (position.clamp(ws_aabb.min, ws_aabb.max).distance(position) < range)
+                        .then(|| (world_from_local, mesh.id()))
+                })
+                .collect();
+            (light.clone(), draws)
+        })
+        .collect();
+
+    let atlas = uniforms.light_shadow_atlas.clone();
+    let width = bevy_window.physical_width().max(1) as i32;
+    let height = bevy_window.physical_height().max(1) as i32;
+    enc.record(move |ctx, world| {
+        #[allow(unexpected_cfgs)]
+        let shader_index = shader_cached!(
+            ctx,
+            "../assets/shaders/light_shadow.vert",
+            "../assets/shaders/light_shadow.frag",
+            &[],
+            &[]
+        )
+        .unwrap();
+
+        unsafe {
+            let framebuffer = if let Some(targets) = world.get_resource::<LightShadowTargets>() {
+                targets.framebuffer
+            } else {
+                let framebuffer = init_light_shadow_targets(
+                    &ctx.gl,
+                    &mut world.resource_mut::<GpuImages>(),
+                    &atlas,
+                );
+                world.insert_resource(LightShadowTargets { framebuffer });
+                framebuffer
+            };
+
+            ctx.gl
+                .bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            ctx.gl.viewport(
+                0,
+                0,
+                LIGHT_SHADOW_ATLAS_SIZE as i32,
+                LIGHT_SHADOW_ATLAS_SIZE as i32,
+            );
+            ctx.gl.disable(glow::BLEND);
+            ctx.gl.enable(glow::DEPTH_TEST);
+            ctx.gl.depth_mask(true);
+            ctx.gl.clear_color(1.0, 1.0, 1.0, 1.0);
+            ctx.gl.clear_depth_f32(1.0);
+            ctx.gl
+                .clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        }
+
+        world.resource_mut::<GpuMeshes>().reset_mesh_bind_cache();
+        ctx.use_cached_program(shader_index);
+        // Both sides, so thin single sided geometry still blocks the light.
+        ctx.set_cull_mode(None);
+
+        for (light, draws) in &lights {
+            ctx.load("light_position_range", light.position_range);
+            for tile in &light.tiles {
+                let x = (tile.tile % LIGHT_SHADOW_TILES_PER_ROW * LIGHT_SHADOW_TILE_SIZE) as i32;
+                let y = (tile.tile / LIGHT_SHADOW_TILES_PER_ROW * LIGHT_SHADOW_TILE_SIZE) as i32;
+                let size = LIGHT_SHADOW_TILE_SIZE as i32;
+                unsafe { ctx.gl.viewport(x, y, size, size) };
+                ctx.load("light_clip_from_world", tile.clip_from_world);
+                for (world_from_local, mesh) in draws {
+                    ctx.load("world_from_local", *world_from_local);
+                    world
+                        .resource_mut::<GpuMeshes>()
+                        .draw_mesh(ctx, *mesh, shader_index);
+                }
+            }
+        }
+
+        unsafe {
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            ctx.gl.viewport(0, 0, width, height);
+        }
+    });
+}
+
+unsafe fn init_light_shadow_targets(
+    gl: &glow::Context,
+    images: &mut GpuImages,
+    atlas: &TextureRef,
+) -> glow::Framebuffer {
+    let size = LIGHT_SHADOW_ATLAS_SIZE as i32;
+    unsafe {
+        let texture = gl.create_texture().unwrap();
+        images.add_texture_set_ref(texture, glow::TEXTURE_2D, atlas);
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA as i32,
+            size,
+            size,
+            0,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            PixelUnpackData::Slice(None),
+        );
+
+        let depth = gl.create_renderbuffer().unwrap();
+        gl.bind_renderbuffer(glow::RENDERBUFFER, Some(depth));
+        gl.renderbuffer_storage(glow::RENDERBUFFER, glow::DEPTH_COMPONENT16, size, size);
+
+        let framebuffer = gl.create_framebuffer().unwrap();
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(texture),
+            0,
+        );
+        gl.framebuffer_renderbuffer(
+            glow::FRAMEBUFFER,
+            glow::DEPTH_ATTACHMENT,
+            glow::RENDERBUFFER,
+            Some(depth),
+        );
+        framebuffer
+    }
+}
@@ -0,0 +1,318 @@
+//! Volumetric shafts from the spot lights `GameLightingUniforms::set_light_shafts` picks, scattered
+//! by the `Fog`. Drawn as a fullscreen pass over the lit scene so they also cross the sky. A
+//! prepass first draws the distance to the view with the light shadow shader, packed the same way,
+//! which the pass marches up to.
+
+use bevy::prelude::*;
+use bgl2::{
+    BevyGlContext, Tex,
+    bevy_standard_material::ViewUniforms,
+    command_encoder::CommandEncoder,
+    prepare_image::{GpuImages, TextureRef},
+    prepare_mesh::GpuMeshes,
+    render::RenderSet,
+    shader_cached,
+};
+use glow::{HasContext, PixelUnpackData};
+
+use crate::{
+    light_shadows::{LightShadowAssignments, LightShadowUniforms},
+    post_process::{HdrTarget, PostProcessSettings, copy_render_target, fullscreen_triangle},
+    prepare_lighting::{DEFAULT_MAX_SHAFT_LIGHTS_DEF, GameLightingUniforms, LightShaftQuality},
+    std_mat_render::Fog,
+};
+
+#[derive(Resource, Default)]
+pub struct LightShaftPlugin;
+
+impl Plugin for LightShaftPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            render_light_shafts
+                .in_set(RenderSet::RenderDebug)
+                .before(copy_render_target),
+        );
+    }
+}
+
+/// The view distance prepass, rebuilt when the window is resized.
+#[derive(Resource)]
+struct LightShaftTargets {
+    width: u32,
+    height: u32,
+    view_distance: TextureRef,
+    framebuffer: glow::Framebuffer,
+    depth: glow::Renderbuffer,
+}
+
+fn render_light_shafts(
+    camera: Single<(&GlobalTransform, &Projection), With<Camera3d>>,
+    meshes: Query<(
+        &ViewVisibility,
+        &GlobalTransform,
+        &Mesh3d,
+        &MeshMaterial3d<StandardMaterial>,
+    )>,
+    materials: Res<Assets<StandardMaterial>>,
+    light_shadows: Res<LightShadowAssignments>,
+    bevy_window: Single<&Window>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    let (camera_trans, projection) = *camera;
+    let Projection::Perspective(perspective) = projection else {
+        return;
+    };
+    let (fov, aspect_ratio, near) = (perspective.fov, perspective.aspect_ratio, perspective.near);
+    let view_from_world = camera_trans.to_matrix().inverse();
+    let view_position = camera_trans.translation();
+    let light_shadows = !light_shadows.is_empty();
+
+    // Blended surfaces don't hide the shafts behind them.
+    let draws: Vec<(Mat4, AssetId<Mesh>)> = meshes
+        .iter()
+        .filter(|(view_vis, ..)| view_vis.get())
+        .filter(|(.., material)| {
+            materials
+                .get(*material)
+                .is_some_and(|m| !matches!(m.alpha_mode, AlphaMode::Blend))
+        })
+        .map(|(_, transform, mesh, _)| (transform.to_matrix(), mesh.id()))
+        .collect();
+
+    let width = bevy_window.physical_width().max(1);
+    let height = bevy_window.physical_height().max(1);
+    enc.record(move |ctx, world| {
+        let lighting = world.resource::<GameLightingUniforms>().clone();
+        let Some(fog) = world.get_resource::<Fog>().cloned() else {
+            return;
+        };
+        if lighting.light_shaft_count == 0
+            || lighting.light_shaft_quality == LightShaftQuality::Off
+            || fog.fog_color.w <= 0.0
+        {
+            return;
+        }
+        let max_distance = lighting.light_shaft_max_distance;
+        let clip_from_world =
+            Mat4::perspective_rh_gl(fov, aspect_ratio, near, max_distance) * view_from_world;
+
+        update_light_shaft_targets(ctx, world, width, height);
+        let (framebuffer, view_distance) = {
+            let targets = world.resource::<LightShaftTargets>();
+            (targets.framebuffer, targets.view_distance.clone())
+        };
+
+        #[allow(unexpected_cfgs)]
+        let prepass_shader = shader_cached!(
+            ctx,
+            "../assets/shaders/light_shadow.vert",
+            "../assets/shaders/light_shadow.frag",
+            &[],
+            &[]
+        )
+        .unwrap();
+
+        unsafe {
+            ctx.gl
+                .bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            ctx.gl.viewport(0, 0, width as i32, height as i32);
+            ctx.gl.disable(glow::BLEND);
+            ctx.gl.enable(glow::DEPTH_TEST);
+            ctx.gl.depth_mask(true);
+            // Unpacks to 1.0, as far as the shafts are marched.
+            ctx.gl.clear_color(1.0, 1.0, 1.0, 1.0);
+            ctx.gl.clear_depth_f32(1.0);
+            ctx.gl
+                .clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        }
+
+        world.resource_mut::<GpuMeshes>().reset_mesh_bind_cache();
+        ctx.use_cached_program(prepass_shader);
+        ctx.set_cull_mode(None);
+        ctx.load("light_clip_from_world", clip_from_world);
+        ctx.load("light_position_range", view_position.extend(max_distance));
+        for (world_from_local, mesh) in &draws {
+            ctx.load("world_from_local", *world_from_local);
+            world
+                .resource_mut::<GpuMeshes>()
+                .draw_mesh(ctx, *mesh, prepass_shader);
+        }
+
+        // Back to the scene, in linear color if it's a float `HdrTarget`.
+        let hdr_target = world
+            .get_resource::<HdrTarget>()
+            .filter(|_| world.resource::<PostProcessSettings>().hdr);
+        let additive = hdr_target.is_some_and(|target| target.float);
+        if let Some(target) = hdr_target {
+            target.bind(ctx, None);
+        } else {
+            unsafe {
+                ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+                ctx.gl.viewport(0, 0, width as i32, height as i32);
+            }
+        }
+
+        #[allow(unexpected_cfgs)]
+        let shader_index = shader_cached!(
+            ctx,
+            "../assets/shaders/post_process.vert",
+            "../assets/shaders/light_shafts.frag",
+            [
+                DEFAULT_MAX_SHAFT_LIGHTS_DEF,
+                lighting.light_shaft_quality.shader_def(),
+                if light_shadows {
+                    ("LIGHT_SHADOWS", "")
+                } else {
+                    ("", "")
+                },
+                if additive { ("ADDITIVE", "") } else { ("", "") },
+            ]
+            .iter(),
+            &[
+                ViewUniforms::bindings(),
+                GameLightingUniforms::bindings(),
+                LightShadowUniforms::bindings(),
+                Fog::bindings(),
+            ]
+        )
+        .unwrap();
+
+        let positions_vbo = fullscreen_triangle(ctx, world);
+        ctx.use_cached_program(shader_index);
+        ctx.map_uniform_set_locations::<ViewUniforms>();
+        ctx.map_uniform_set_locations::<GameLightingUniforms>();
+        ctx.map_uniform_set_locations::<Fog>();
+        let images = world.resource::<GpuImages>();
+        ctx.bind_uniforms_set(images, world.resource::<ViewUniforms>());
+        ctx.bind_uniforms_set(images, &lighting);
+        ctx.bind_uniforms_set(images, &fog);
+        if light_shadows {
+            ctx.map_uniform_set_locations::<LightShadowUniforms>();
+            ctx.bind_uniforms_set(images, world.resource::<LightShadowUniforms>());
+        }
+        ctx.load_tex(images, "view_distance", &Tex::Ref(view_distance));
+        ctx.load("world_from_clip", clip_from_world.inverse());
+
+        unsafe {
+            ctx.start_alpha_blend();
+            if additive {
+                ctx.gl.blend_func(glow::ONE, glow::ONE);
+            } else {
+                ctx.gl.blend_func(glow::ONE, glow::ONE_MINUS_SRC_COLOR);
+            }
+            ctx.gl.disable(glow::DEPTH_TEST);
+
+            let pos_loc = ctx.get_attrib_location(shader_index, "a_position").unwrap();
+            ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(positions_vbo));
+            ctx.gl.enable_vertex_attrib_array(pos_loc);
+            ctx.gl
+                .vertex_attrib_pointer_f32(pos_loc, 2, glow::FLOAT, false, 8, 0);
+            ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+
+            ctx.gl
+                .blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+        }
+    });
+}
+
+/// Makes sure `LightShaftTargets` matches the window.
+fn update_light_shaft_targets(ctx: &mut BevyGlContext, world: &mut World, width: u32, height: u32) {
+    if world
+        .get_resource::<LightShaftTargets>()
+        .is_some_and(|targets| targets.width == width && targets.height == height)
+    {
+        return;
+    }
+    let view_distance = if let Some(old) = world.remove_resource::<LightShaftTargets>() {
+        unsafe {
+            ctx.gl.delete_framebuffer(old.framebuffer);
+            ctx.gl.delete_renderbuffer(old.depth);
+            if let Some((tex, _target)) = world
+                .resource_mut::<GpuImages>()
+                .texture_from_ref(&old.view_distance)
+            {
+                ctx.gl.delete_texture(tex);
+            }
+        }
+        old.view_distance
+    } else {
+        TextureRef::new()
+    };
+
+    unsafe {
+        let gl = &ctx.gl;
+        let texture = gl.create_texture().unwrap();
+        world.resource_mut::<GpuImages>().add_texture_set_ref(
+            texture,
+            glow::TEXTURE_2D,
+            &view_distance,
+        );
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA as i32,
+            width as i32,
+            height as i32,
+            0,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            PixelUnpackData::Slice(None),
+        );
+
+        let depth = gl.create_renderbuffer().unwrap();
+        gl.bind_renderbuffer(glow::RENDERBUFFER, Some(depth));
+        gl.renderbuffer_storage(
+            glow::RENDERBUFFER,
+            glow::DEPTH_COMPONENT16,
+            width as i32,
+            height as i32,
+        );
+
+        let framebuffer = gl.create_framebuffer().unwrap();
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(texture),
+            0,
+        );
+        gl.framebuffer_renderbuffer(
+            glow::FRAMEBUFFER,
+            glow::DEPTH_ATTACHMENT,
+            glow::RENDERBUFFER,
+            Some(depth),
+        );
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+        world.insert_resource(LightShaftTargets {
+            width,
+            height,
+            view_distance,
+            framebuffer,
+            depth,
+        });
+    }
+}
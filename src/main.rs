@@ -1,28 +1,51 @@
 pub mod assets;
+#[cfg(feature = "asset_baking")]
+pub mod bake_cli;
+#[cfg(feature = "asset_baking")]
+pub mod bake_hash;
 pub mod cascade;
+pub mod cascade_bake;
+pub mod cascade_debug;
+pub mod cascade_manifest;
 pub mod copy_depth_prepass;
 pub mod draw_debug;
+pub mod fog_volume;
+pub mod light_shadows;
+pub mod light_shafts;
 pub mod menu;
 pub mod physics;
 pub mod player;
 pub mod post_process;
 pub mod prepare_lighting;
+#[cfg(feature = "asset_baking")]
+pub mod probe_baker;
+#[cfg(feature = "asset_baking")]
+pub mod probe_dilate;
+#[cfg(feature = "asset_baking")]
+pub mod reference_pt;
+#[cfg(feature = "asset_baking")]
+pub mod rt_scene;
 pub mod scene_falling;
 pub mod scene_hallway;
 pub mod scene_store;
 pub mod scene_temple;
 pub mod scene_underwater;
 pub mod std_mat_render;
+pub mod time_of_day;
 
 use argh::FromArgs;
 
+use std::time::Duration;
+
 use bevy::{
+    app::ScheduleRunnerPlugin,
     asset::AssetMetaCheck,
     camera_controller::free_camera::FreeCameraPlugin,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::*,
     render::{RenderPlugin, settings::WgpuSettings},
-    winit::WinitSettings,
+    window::ExitCondition,
+    winit::{WinitPlugin, WinitSettings},
 };
 use bevy_asset_loader::loading_state::{
     LoadingState, LoadingStateAppExt, config::ConfigureLoadingState,
@@ -47,18 +70,15 @@ use bgl2::{egui_plugin::GlowEguiPlugin, render::register_render_system};
 use iyes_progress::ProgressPlugin;
 
 #[cfg(feature = "asset_baking")]
-use light_volume_baker::{
-    CascadeData,
-    cpu_probes::{CpuProbesPlugin, RunProbeDebug},
-    pt_reference_camera::PtReferencePlugin,
-    rt_scene::{RtEnvColor, RtScenePlugin},
-    softbuffer_plugin::SoftBufferPlugin,
-};
+use crate::{probe_baker::ProbeBakerPlugin, reference_pt::ReferencePtPlugin, rt_scene::RtEnvColor};
 
 use crate::{
     assets::{AudioAssets, SceneAssets},
     cascade::ConvertCascadePlugin,
-    draw_debug::DrawDebugPlugin,
+    draw_debug::{DebugLines, DrawDebugPlugin},
+    fog_volume::FogVolumePlugin,
+    light_shadows::LightShadowPlugin,
+    light_shafts::LightShaftPlugin,
     menu::MenuPlugin,
     player::PlayerControllerPlugin,
     post_process::{PostProcessPlugin, PostProcessSettings},
@@ -67,20 +87,27 @@ use crate::{
     scene_hallway::HallwayGameplayPlugin,
     scene_store::StoreSceneGameplayPlugin,
     scene_underwater::UnderwaterGameplayPlugin,
-    std_mat_render::{Fog, generate_tangets},
+    std_mat_render::{Fog, advance_fog_time, generate_tangets},
+    time_of_day::TimeOfDayPlugin,
 };
 
 #[derive(FromArgs, Resource, Clone, Default)]
 /// Config
 pub struct Args {
     #[cfg(feature = "asset_baking")]
-    /// render using reference PT, see what the probes see
-    #[argh(switch)]
-    reference_pt: bool,
+    /// bake the probes of a scene (store, hallway, temple, underwater, falling), a comma separated
+    /// list of them or all, without a window, then exit
+    #[argh(option)]
+    pub bake: Option<String>,
     #[cfg(feature = "asset_baking")]
-    /// cpu render first cascade
+    /// render the camera view with the CPU reference path tracer on F6, or after baking each
+    /// scene with --bake
     #[argh(switch)]
-    probe_debug: bool,
+    pub reference_pt: bool,
+    #[cfg(feature = "dev")]
+    /// start with the cascade debug overlay showing the probes, colored by the CPU lookup
+    #[argh(switch)]
+    pub probe_debug: bool,
 }
 
 #[derive(Default, States, Debug, Clone, PartialEq, Eq, Hash)]
@@ -103,6 +130,60 @@ fn main() {
     #[allow(unused)]
     let args: Args = argh::from_env();
 
+    #[cfg(feature = "asset_baking")]
+    let bake_scenes = match args.bake.as_deref().map(bake_cli::parse_bake_scenes) {
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        Some(Ok(scenes)) => Some(scenes),
+        None => None,
+    };
+    #[cfg(feature = "asset_baking")]
+    let headless_bake = bake_scenes.is_some();
+    #[cfg(not(feature = "asset_baking"))]
+    let headless_bake = false;
+
+    let mut default_plugins = DefaultPlugins
+        .set(RenderPlugin {
+            render_creation: WgpuSettings {
+                backends: None,
+                ..default()
+            }
+            .into(),
+            ..default()
+        })
+        .set(WindowPlugin {
+            primary_window: (!headless_bake).then(|| Window {
+                present_mode: bevy::window::PresentMode::AutoVsync,
+                ..default()
+            }),
+            exit_condition: if headless_bake {
+                ExitCondition::DontExit
+            } else {
+                ExitCondition::OnAllClosed
+            },
+            ..default()
+        })
+        .set(AssetPlugin {
+            meta_check: AssetMetaCheck::Never,
+            #[cfg(feature = "dev")]
+            unapproved_path_mode: bevy::asset::UnapprovedPathMode::Allow,
+            #[cfg(not(feature = "dev"))]
+            unapproved_path_mode: bevy::asset::UnapprovedPathMode::Forbid,
+            #[cfg(feature = "dev")]
+            watch_for_changes_override: Some(true),
+            ..default()
+        });
+    if headless_bake {
+        default_plugins =
+            default_plugins
+                .disable::<WinitPlugin>()
+                .add(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                    1.0 / 60.0,
+                )));
+    }
+
     let mut app = App::new();
     app.insert_resource(args.clone());
     #[cfg(feature = "asset_baking")]
@@ -112,30 +193,7 @@ fn main() {
         .insert_resource(WinitSettings::continuous())
         .insert_resource(GlobalAmbientLight::NONE)
         .add_plugins((
-            DefaultPlugins
-                .set(RenderPlugin {
-                    render_creation: WgpuSettings {
-                        backends: None,
-                        ..default()
-                    }
-                    .into(),
-                    ..default()
-                })
-                .set(WindowPlugin {
-                    primary_window: Some(Window {
-                        present_mode: bevy::window::PresentMode::AutoVsync,
-                        ..default()
-                    }),
-                    ..default()
-                })
-                .set(AssetPlugin {
-                    meta_check: AssetMetaCheck::Never,
-                    #[cfg(feature = "dev")]
-                    unapproved_path_mode: bevy::asset::UnapprovedPathMode::Allow,
-                    #[cfg(not(feature = "dev"))]
-                    unapproved_path_mode: bevy::asset::UnapprovedPathMode::Forbid,
-                    ..default()
-                }),
+            default_plugins,
             FreeCameraPlugin,
             LogDiagnosticsPlugin::default(),
             FrameTimeDiagnosticsPlugin::default(),
@@ -144,51 +202,43 @@ fn main() {
 
     #[cfg(feature = "asset_baking")]
     {
-        use light_volume_baker::{cpu_probes::CpuProbeSamples, gpu_rt::GpuRtPlugin};
-
-        app.add_plugins(RtScenePlugin);
-        if args.probe_debug {
-            app.init_resource::<RunProbeDebug>();
-        }
-        app.insert_resource(CpuProbeSamples(0))
-            .add_plugins((CpuProbesPlugin, GpuRtPlugin));
+        app.add_plugins(ProbeBakerPlugin);
         if args.reference_pt {
-            app.add_plugins(PtReferencePlugin);
+            app.add_plugins(ReferencePtPlugin);
         }
     }
 
-    #[cfg(feature = "asset_baking")]
-    let bgl2_render = !(args.reference_pt || args.probe_debug);
-    #[cfg(not(feature = "asset_baking"))]
-    let bgl2_render = true;
-
-    #[cfg(feature = "asset_baking")]
-    if args.reference_pt || args.probe_debug {
-        app.add_plugins(SoftBufferPlugin);
-    }
+    app.add_plugins(ProgressPlugin::<SceneState>::new())
+        .add_loading_state(
+            LoadingState::new(SceneState::Loading)
+                .continue_to_state(SceneState::Loaded)
+                .load_collection::<SceneAssets>()
+                .load_collection::<AudioAssets>(),
+        )
+        .add_plugins((
+            PlayerControllerPlugin,
+            StoreSceneGameplayPlugin,
+            HallwayGameplayPlugin,
+            UnderwaterGameplayPlugin,
+            FallingGameplayPlugin,
+            SeedlingPlugin::default(),
+        ));
 
+    let bgl2_render = !headless_bake;
     if bgl2_render {
-        app.add_plugins(ProgressPlugin::<SceneState>::new())
-            .add_loading_state(
-                LoadingState::new(SceneState::Loading)
-                    .continue_to_state(SceneState::Loaded)
-                    .load_collection::<SceneAssets>()
-                    .load_collection::<AudioAssets>(),
-            )
-            .init_resource::<DrawsSortedByMaterial>()
+        app.init_resource::<DrawsSortedByMaterial>()
             .add_plugins((
                 GlowEguiPlugin,
                 OpenGLRenderPlugins,
                 PrepareLightingPlugin,
+                LightShadowPlugin,
+                LightShaftPlugin,
+                FogVolumePlugin,
+                // Not in headless bakes, those use the sun as the scene loader leaves it.
+                TimeOfDayPlugin,
                 DrawDebugPlugin,
                 PostProcessPlugin,
-                PlayerControllerPlugin,
-                StoreSceneGameplayPlugin,
-                HallwayGameplayPlugin,
-                UnderwaterGameplayPlugin,
-                FallingGameplayPlugin,
                 MenuPlugin,
-                SeedlingPlugin::default(),
             ))
             .add_systems(
                 PostUpdate,
@@ -210,21 +260,58 @@ fn main() {
             (|mut enc: ResMut<CommandEncoder>| {
                 enc.record(|ctx, _world| {
                     ctx.add_shader_include("game::caustics", include_str!("shaders/caustics.glsl"));
+                    ctx.add_shader_include(
+                        "game::light_shadows",
+                        include_str!("shaders/light_shadows.glsl"),
+                    );
+                    ctx.add_shader_include("game::hdr", include_str!("shaders/hdr.glsl"));
+                    ctx.add_shader_include(
+                        "game::fog_noise",
+                        include_str!("shaders/fog_noise.glsl"),
+                    );
                 });
             })
             .in_set(RenderSet::Pipeline),
         );
         #[cfg(feature = "dev")]
-        app.add_systems(EguiPrimaryContextPass, drag_drop_gltf);
+        app.add_systems(EguiPrimaryContextPass, drag_drop_gltf)
+            .add_plugins((
+                physics::ColliderDebugPlugin,
+                cascade_debug::CascadeDebugPlugin,
+            ));
+        #[cfg(feature = "dev")]
+        if args.probe_debug {
+            app.insert_resource(cascade_debug::CascadeDebugSettings {
+                mode: cascade_debug::CascadeDebugMode::Probes,
+            });
+        }
+    } else {
+        // Gameplay and cascade systems still write debug lines.
+        app.init_resource::<DebugLines>();
     }
 
     app.init_resource::<Fog>()
-        .add_plugins(ConvertCascadePlugin)
-        .add_systems(
+        .add_plugins((
+            ConvertCascadePlugin,
+            cascade_manifest::CascadeManifestPlugin,
+        ))
+        .add_systems(Update, (generate_tangets, advance_fog_time));
+
+    if headless_bake {
+        app.add_systems(OnEnter(SceneState::Loaded), setup);
+    } else {
+        app.add_systems(
             OnEnter(SceneState::Loaded),
             (setup, scene_store::load_store).chain(),
-        )
-        .add_systems(Update, generate_tangets);
+        );
+    }
+    #[cfg(feature = "asset_baking")]
+    if let Some(scenes) = bake_scenes {
+        app.add_plugins(bake_cli::BakeCliPlugin {
+            scenes,
+            reference_pt: args.reference_pt,
+        });
+    }
 
     #[cfg(not(target_arch = "wasm32"))]
     app.add_plugins(MipmapGeneratorPlugin)
@@ -269,12 +356,16 @@ fn drag_drop_gltf(
                 .to_str()
                 .unwrap_or_default()
                 .to_string();
-            commands
-                .spawn((
-                    SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset(path))),
-                    SceneBakeName(scene_bake_name),
-                ))
-                .observe(cascade::blender_cascades);
+            let mut ecmds = commands.spawn((
+                SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset(path.clone()))),
+                SceneBakeName(scene_bake_name),
+            ));
+            ecmds.observe(cascade::blender_cascades);
+            if cascade_manifest::sibling_manifest_path(path_buf).exists() {
+                ecmds.insert(cascade_manifest::SceneCascadeManifest(
+                    asset_server.load(cascade_manifest::sibling_manifest_path(&path)),
+                ));
+            }
         }
     }
 }
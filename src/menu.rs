@@ -5,7 +5,12 @@ use bevy_egui::{
 };
 use bevy_fps_controller::controller::FpsController;
 
-use crate::{SceneState, despawn_scene_contents, scene_store::load_store};
+use crate::{
+    SceneState, despawn_scene_contents,
+    post_process::{PostProcessSettings, TonemapOperator, TonemapSettings},
+    prepare_lighting::{LightShaftQuality, LightShaftSettings},
+    scene_store::load_store,
+};
 
 pub struct MenuPlugin;
 impl Plugin for MenuPlugin {
@@ -22,7 +27,10 @@ pub fn menu_ui(
     mut contexts: EguiContexts,
     mut app_exit: MessageWriter<AppExit>,
     state: Res<State<SceneState>>,
-    #[cfg(feature = "asset_baking")] cascades: Query<Entity, With<light_volume_baker::CascadeData>>,
+    mut light_shafts: ResMut<LightShaftSettings>,
+    mut tonemap: ResMut<TonemapSettings>,
+    post_process: Res<PostProcessSettings>,
+    #[cfg(feature = "asset_baking")] cascades: Query<Entity, With<crate::cascade::CascadeInput>>,
     #[cfg(feature = "dev")] mut camera: Option<
         Single<&mut bevy::camera_controller::free_camera::FreeCameraState>,
     >,
@@ -144,6 +152,31 @@ pub fn menu_ui(
                 window.mode = WindowMode::Windowed;
             }
 
+            ui.allocate_space(egui::vec2(width, 40.0));
+            ui.label("LIGHT SHAFTS");
+            for quality in LightShaftQuality::ALL {
+                if ui
+                    .radio(light_shafts.quality == quality, quality.name())
+                    .clicked()
+                {
+                    light_shafts.quality = quality;
+                }
+            }
+
+            // Only scenes with hdr have a tonemap pass to choose for.
+            if post_process.hdr {
+                ui.allocate_space(egui::vec2(width, 40.0));
+                ui.label("TONEMAPPING");
+                for operator in TonemapOperator::ALL {
+                    if ui
+                        .radio(tonemap.operator == operator, operator.name())
+                        .clicked()
+                    {
+                        tonemap.operator = operator;
+                    }
+                }
+            }
+
             ui.allocate_space(egui::vec2(width, 40.0));
 
             if ui.button("RESTART GAME").clicked() {
@@ -158,15 +191,10 @@ pub fn menu_ui(
             {
                 #[cfg(feature = "asset_baking")]
                 {
-                    use light_volume_baker::gpu_rt::NeedsGpuBake;
-                    use light_volume_baker::{NeedsCourseBake, NeedsFineBake};
+                    use crate::probe_baker::NeedsProbeBake;
                     if ui.button("Rebake All").clicked() {
                         for entity in &cascades {
-                            commands.entity(entity).insert((
-                                NeedsGpuBake,
-                                NeedsCourseBake,
-                                NeedsFineBake,
-                            ));
+                            commands.entity(entity).insert(NeedsProbeBake);
                         }
                     }
                 }
@@ -1,7 +1,11 @@
+use std::f32::consts::TAU;
+
 use avian3d::prelude::*;
 use bevy::{prelude::*, scene::SceneInstanceReady};
 use bevy_mod_mesh_tools::mesh_append;
-use bgl2::mesh_util::get_attribute_f32x3;
+use bgl2::{mesh_util::get_attribute_f32x3, render::RenderSet};
+
+use crate::draw_debug::DebugLines;
 
 pub fn tri_mesh_collider(
     scene_ready: On<SceneInstanceReady>,
@@ -106,3 +110,141 @@ pub fn convex_hull_dyn_collider_indv(
         }
     }
 }
+
+/// Dev toggle for drawing every collider through `DebugLines`. Press F3 to toggle.
+#[derive(Resource, Default)]
+pub struct ColliderDebugPlugin;
+
+impl Plugin for ColliderDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ColliderDebugSettings>().add_systems(
+            PostUpdate,
+            (toggle_collider_debug, draw_collider_debug)
+                .chain()
+                .in_set(RenderSet::Prepare),
+        );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ColliderDebugSettings {
+    pub enabled: bool,
+}
+
+const TRIMESH_COLOR: Vec3 = vec3(1.0, 0.8, 0.1);
+const CONVEX_HULL_COLOR: Vec3 = vec3(0.1, 0.9, 1.0);
+const CYLINDER_COLOR: Vec3 = vec3(1.0, 0.2, 0.9);
+const OTHER_COLLIDER_COLOR: Vec3 = vec3(1.0, 1.0, 1.0);
+
+fn toggle_collider_debug(
+    key: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<ColliderDebugSettings>,
+) {
+    if key.just_pressed(KeyCode::F3) {
+        settings.enabled = !settings.enabled;
+    }
+}
+
+fn draw_collider_debug(
+    settings: Res<ColliderDebugSettings>,
+    colliders: Query<(&Collider, &GlobalTransform, Option<&ColliderOf>)>,
+    bodies: Query<(&RigidBody, Has<Sleeping>)>,
+    mut debug: ResMut<DebugLines>,
+) {
+    if !settings.enabled {
+        return;
+    }
+    for (collider, transform, collider_of) in &colliders {
+        let (body, sleeping) = collider_of
+            .and_then(|collider_of| bodies.get(collider_of.body).ok())
+            .map(|(body, sleeping)| (*body, sleeping))
+            .unwrap_or((RigidBody::Static, false));
+
+        // Scale is already applied by shape_scaled(), only use rotation and translation.
+        let (_scale, rotation, translation) = transform.to_scale_rotation_translation();
+        let world_from_collider = Affine3A::from_rotation_translation(rotation, translation);
+
+        let shape = collider.shape_scaled();
+        let base_color = if shape.as_trimesh().is_some() {
+            TRIMESH_COLOR
+        } else if shape.as_convex_polyhedron().is_some() {
+            CONVEX_HULL_COLOR
+        } else if shape.as_cylinder().is_some() {
+            CYLINDER_COLOR
+        } else {
+            OTHER_COLLIDER_COLOR
+        };
+        let color = collider_debug_color(base_color, body, sleeping);
+
+        if let Some(trimesh) = shape.as_trimesh() {
+            let vertices = trimesh.vertices();
+            for tri in trimesh.indices() {
+                let p = tri.map(|i| {
+                    let v = vertices[i as usize];
+                    world_from_collider.transform_point3(vec3(v.x, v.y, v.z))
+                });
+                debug.line(p[0], p[1], color);
+                debug.line(p[1], p[2], color);
+                debug.line(p[2], p[0], color);
+            }
+        } else if let Some(convex) = shape.as_convex_polyhedron() {
+            let (vertices, indices) = convex.to_trimesh();
+            for tri in indices {
+                let p = tri.map(|i| {
+                    let v = vertices[i as usize];
+                    world_from_collider.transform_point3(vec3(v.x, v.y, v.z))
+                });
+                debug.line(p[0], p[1], color);
+                debug.line(p[1], p[2], color);
+                debug.line(p[2], p[0], color);
+            }
+        } else if let Some(cylinder) = shape.as_cylinder() {
+            debug_cylinder(
+                &mut debug,
+                world_from_collider,
+                cylinder.half_height,
+                cylinder.radius,
+                color,
+            );
+        } else {
+            let aabb = collider.aabb(translation, rotation);
+            debug.aabb(
+                obvhs::aabb::Aabb::new(aabb.min.into(), aabb.max.into()),
+                color,
+            );
+        }
+    }
+}
+
+/// Static bodies are dimmed, sleeping bodies are desaturated towards grey.
+fn collider_debug_color(base_color: Vec3, body: RigidBody, sleeping: bool) -> Vec3 {
+    let mut color = match body {
+        RigidBody::Static => base_color * 0.4,
+        RigidBody::Dynamic | RigidBody::Kinematic => base_color,
+    };
+    if sleeping {
+        color = color.lerp(Vec3::splat(0.3), 0.7);
+    }
+    color
+}
+
+fn debug_cylinder(
+    debug: &mut DebugLines,
+    world_from_collider: Affine3A,
+    half_height: f32,
+    radius: f32,
+    color: Vec3,
+) {
+    let segments = 16;
+    let point = |i: u32, y: f32| {
+        let angle = i as f32 / segments as f32 * TAU;
+        world_from_collider.transform_point3(vec3(angle.cos() * radius, y, angle.sin() * radius))
+    };
+    for i in 0..segments {
+        debug.line(point(i, -half_height), point(i + 1, -half_height), color);
+        debug.line(point(i, half_height), point(i + 1, half_height), color);
+        if i % 4 == 0 {
+            debug.line(point(i, -half_height), point(i, half_height), color);
+        }
+    }
+}
@@ -15,17 +15,256 @@ pub struct PostProcessPlugin;
 
 impl Plugin for PostProcessPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            PostUpdate,
-            (copy_render_target, render_post_process)
-                .chain()
-                .in_set(RenderSet::RenderDebug),
-        );
+        app.init_resource::<TonemapSettings>()
+            .add_systems(PostUpdate, update_hdr_target.in_set(RenderSet::Prepare))
+            .add_systems(
+                PostUpdate,
+                (copy_render_target, render_post_process)
+                    .chain()
+                    .in_set(RenderSet::RenderDebug),
+            );
     }
 }
-#[derive(Resource, Default)]
+/// Effects applied in order to the tonemapped frame. Scenes replace the whole stack on load.
+/// Consecutive effects that only read their own pixel share a draw, see `PostEffectKind`.
+#[derive(Resource, Clone, Default)]
 pub struct PostProcessSettings {
-    pub enable: bool,
+    pub effects: Vec<PostEffect>,
+    /// std_mat draws linear color into the `HdrTarget` instead of tonemapping itself, and a
+    /// tonemap pass using `TonemapSettings` runs before the effects.
+    pub hdr: bool,
+    /// In stops, only used with `hdr`.
+    pub exposure: f32,
+}
+
+impl PostProcessSettings {
+    pub fn new(effects: impl IntoIterator<Item = PostEffectKind>) -> Self {
+        PostProcessSettings {
+            effects: effects.into_iter().map(PostEffect::from).collect(),
+            ..default()
+        }
+    }
+
+    pub fn with_hdr(mut self, exposure: f32) -> Self {
+        self.hdr = true;
+        self.exposure = exposure;
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.hdr || self.effects.iter().any(|effect| effect.enabled)
+    }
+}
+
+/// The user's choice, applies to scenes with `PostProcessSettings::hdr`.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct TonemapSettings {
+    pub operator: TonemapOperator,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TonemapOperator {
+    /// Same curve std_mat uses without `hdr`.
+    #[default]
+    AgX,
+    Aces,
+    Reinhard,
+}
+
+impl TonemapOperator {
+    pub const ALL: [TonemapOperator; 3] = [
+        TonemapOperator::AgX,
+        TonemapOperator::Aces,
+        TonemapOperator::Reinhard,
+    ];
+
+    fn shader_def(self) -> (&'static str, &'static str) {
+        match self {
+            TonemapOperator::AgX => ("TONEMAP_AGX", ""),
+            TonemapOperator::Aces => ("TONEMAP_ACES", ""),
+            TonemapOperator::Reinhard => ("TONEMAP_REINHARD", ""),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TonemapOperator::AgX => "AGX",
+            TonemapOperator::Aces => "ACES",
+            TonemapOperator::Reinhard => "REINHARD",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PostEffect {
+    pub enabled: bool,
+    pub kind: PostEffectKind,
+}
+
+impl From<PostEffectKind> for PostEffect {
+    fn from(kind: PostEffectKind) -> Self {
+        PostEffect {
+            enabled: true,
+            kind,
+        }
+    }
+}
+
+/// Vignette, film grain, color tint and scanlines only read the pixel they write, so a run of
+/// them is applied in one draw (in that order, a run out of order is split). Chromatic
+/// aberration and blur sample around the pixel and always start a new draw.
+#[derive(Clone, Copy, Debug)]
+pub enum PostEffectKind {
+    /// Darkens towards the corners, starting at `radius` (0 center, 1 corner).
+    Vignette {
+        strength: f32,
+        radius: f32,
+    },
+    FilmGrain {
+        strength: f32,
+    },
+    /// Ghosted copies offset diagonally by `offset` (in uv), the red channel only keeps the
+    /// center sample.
+    ChromaticAberration {
+        offset: f32,
+    },
+    /// Multiplies the srgb output with `color`.
+    ColorTint {
+        color: Color,
+        strength: f32,
+    },
+    /// `count` dark lines over the screen height.
+    Scanlines {
+        count: f32,
+        strength: f32,
+    },
+    /// 3x3 gaussian with taps `radius` pixels apart.
+    Blur {
+        radius: f32,
+    },
+}
+
+impl PostEffectKind {
+    /// Where post_process.frag applies the effect, None if it samples around the pixel.
+    fn pixel_order(&self) -> Option<u32> {
+        match self {
+            PostEffectKind::Vignette { .. } => Some(0),
+            PostEffectKind::FilmGrain { .. } => Some(1),
+            PostEffectKind::ColorTint { .. } => Some(2),
+            PostEffectKind::Scanlines { .. } => Some(3),
+            PostEffectKind::ChromaticAberration { .. } | PostEffectKind::Blur { .. } => None,
+        }
+    }
+
+    fn shader_def(&self) -> (&'static str, &'static str) {
+        match self {
+            PostEffectKind::Vignette { .. } => ("VIGNETTE", ""),
+            PostEffectKind::FilmGrain { .. } => ("FILM_GRAIN", ""),
+            PostEffectKind::ChromaticAberration { .. } => ("CHROMATIC_ABERRATION", ""),
+            PostEffectKind::ColorTint { .. } => ("COLOR_TINT", ""),
+            PostEffectKind::Scanlines { .. } => ("SCANLINES", ""),
+            PostEffectKind::Blur { .. } => ("BLUR", ""),
+        }
+    }
+
+    fn load_uniforms(&self, ctx: &mut BevyGlContext, time: f32, texel_size: Vec2) {
+        match *self {
+            PostEffectKind::Vignette { strength, radius } => {
+                ctx.load("vignette_params", vec4(strength, radius, 0.0, 0.0));
+            }
+            PostEffectKind::FilmGrain { strength } => {
+                ctx.load("grain_params", vec4(strength, 0.0, 0.0, 0.0));
+                ctx.load("time", time);
+            }
+            PostEffectKind::ChromaticAberration { offset } => {
+                ctx.load("aberration_params", vec4(offset, 0.0, 0.0, 0.0));
+            }
+            PostEffectKind::ColorTint { color, strength } => {
+                ctx.load("tint_params", vec4(strength, 0.0, 0.0, 0.0));
+                ctx.load("tint_color", Vec4::from(color.to_srgba().to_f32_array()));
+            }
+            PostEffectKind::Scanlines { count, strength } => {
+                ctx.load("scanline_params", vec4(count, strength, 0.0, 0.0));
+            }
+            PostEffectKind::Blur { radius } => {
+                ctx.load("blur_params", vec4(radius, 0.0, 0.0, 0.0));
+                ctx.load("texel_size", texel_size);
+            }
+        }
+    }
+}
+
+/// A single fullscreen draw of the chain.
+#[derive(Clone, Default)]
+struct PostPass {
+    /// Chromatic aberration or blur, replacing the fetch of the pixel.
+    sampled: Option<PostEffectKind>,
+    /// Decodes the `hdr` scene and tonemaps it with this linear exposure.
+    tonemap: Option<(TonemapOperator, f32)>,
+    /// Sorted by `PostEffectKind::pixel_order`.
+    pixel_effects: Vec<PostEffectKind>,
+}
+
+impl PostPass {
+    fn build(settings: &PostProcessSettings, tonemap: &TonemapSettings) -> Vec<PostPass> {
+        let mut passes = Vec::new();
+        if settings.hdr {
+            passes.push(PostPass {
+                tonemap: Some((tonemap.operator, settings.exposure.exp2())),
+                ..default()
+            });
+        }
+        let effects = settings.effects.iter().filter(|effect| effect.enabled);
+        for effect in effects.map(|effect| effect.kind) {
+            let Some(order) = effect.pixel_order() else {
+                passes.push(PostPass {
+                    sampled: Some(effect),
+                    ..default()
+                });
+                continue;
+            };
+            if let Some(pass) = passes.last_mut()
+                && pass
+                    .pixel_effects
+                    .last()
+                    .is_none_or(|last| last.pixel_order() < Some(order))
+            {
+                pass.pixel_effects.push(effect);
+            } else {
+                passes.push(PostPass {
+                    pixel_effects: vec![effect],
+                    ..default()
+                });
+            }
+        }
+        passes
+    }
+
+    fn shader_defs(&self, float_target: bool) -> Vec<(&'static str, &'static str)> {
+        let mut defs = Vec::new();
+        if let Some((operator, _)) = self.tonemap {
+            defs.extend([("TONEMAP", ""), operator.shader_def()]);
+            if float_target {
+                defs.push(("HDR_FLOAT_TARGET", ""));
+            }
+        }
+        defs.extend(
+            self.sampled
+                .iter()
+                .chain(&self.pixel_effects)
+                .map(PostEffectKind::shader_def),
+        );
+        defs
+    }
+
+    fn load_uniforms(&self, ctx: &mut BevyGlContext, time: f32, texel_size: Vec2) {
+        if let Some((_, exposure)) = self.tonemap {
+            ctx.load("exposure", exposure);
+        }
+        for effect in self.sampled.iter().chain(&self.pixel_effects) {
+            effect.load_uniforms(ctx, time, texel_size);
+        }
+    }
 }
 
 #[derive(Resource)]
@@ -33,66 +272,170 @@ struct PostProcessBuffers {
     positions_vbo: glow::Buffer,
 }
 
-fn render_post_process(
+/// A triangle covering the screen, for post_process.vert's `a_position`.
+pub fn fullscreen_triangle(ctx: &mut BevyGlContext, world: &mut World) -> glow::Buffer {
+    if let Some(buffers) = world.get_resource::<PostProcessBuffers>() {
+        return buffers.positions_vbo;
+    }
+    unsafe {
+        let positions_vbo = ctx.gl.create_buffer().unwrap();
+        let positions = [-1.0f32, -1.0, 3.0, -1.0, -1.0, 3.0];
+        ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(positions_vbo));
+        ctx.gl.buffer_data_u8_slice(
+            glow::ARRAY_BUFFER,
+            cast_slice(&positions),
+            glow::STATIC_DRAW,
+        );
+        world.insert_resource(PostProcessBuffers { positions_vbo });
+        positions_vbo
+    }
+}
+
+/// The passes ping pong between the `RenderTexture` and `extra`, the last one draws to the
+/// screen. Rebuilt when the window is resized.
+#[derive(Resource)]
+struct PostProcessTargets {
+    width: u32,
+    height: u32,
+    extra: TextureRef,
+    /// Rendering into the `RenderTexture` and into `extra`.
+    framebuffers: [glow::Framebuffer; 2],
+}
+
+pub fn render_post_process(
     mut enc: ResMut<CommandEncoder>,
     render_texture: If<Res<RenderTexture>>,
     settings: Res<PostProcessSettings>,
+    tonemap: Res<TonemapSettings>,
+    time: Res<Time>,
 ) {
-    if !settings.enable {
+    let passes = PostPass::build(&settings, &tonemap);
+    if passes.is_empty() {
         return;
     }
     let render_texture = render_texture.clone();
-    enc.record(|ctx, world| {
-        #[allow(unexpected_cfgs)]
-        let shader_index = shader_cached!(
-            ctx,
-            "../assets/shaders/post_process.vert",
-            "../assets/shaders/post_process.frag",
-            &[],
-            &[]
-        )
-        .unwrap();
+    let time = time.elapsed_secs_wrapped();
+    enc.record(move |ctx, world| {
+        let hdr_source = world
+            .get_resource::<HdrTarget>()
+            .map(|target| (target.texture.clone(), target.float));
+        if passes[0].tonemap.is_some() && hdr_source.is_none() {
+            return;
+        }
+        let float_target = hdr_source.as_ref().is_some_and(|(_, float)| *float);
+        if passes.len() > 1 {
+            update_post_process_targets(ctx, world, &render_texture);
+        }
+        let texel_size = 1.0 / vec2(render_texture.width as f32, render_texture.height as f32);
+        let positions_vbo = fullscreen_triangle(ctx, world);
         unsafe {
-            let positions_vbo = if let Some(buffers) = world.get_resource::<PostProcessBuffers>() {
-                buffers.positions_vbo
-            } else {
-                let positions_vbo = ctx.gl.create_buffer().unwrap();
-                let positions = [-1.0f32, -1.0, 3.0, -1.0, -1.0, 3.0];
-                ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(positions_vbo));
-                ctx.gl.buffer_data_u8_slice(
-                    glow::ARRAY_BUFFER,
-                    cast_slice(&positions),
-                    glow::STATIC_DRAW,
-                );
-                world.insert_resource(PostProcessBuffers { positions_vbo });
-                positions_vbo
-            };
+            for (i, pass) in passes.iter().enumerate() {
+                #[allow(unexpected_cfgs)]
+                let shader_index = shader_cached!(
+                    ctx,
+                    "../assets/shaders/post_process.vert",
+                    "../assets/shaders/post_process.frag",
+                    pass.shader_defs(float_target).iter(),
+                    &[]
+                )
+                .unwrap();
 
-            ctx.use_cached_program(shader_index);
+                let (source, target) = if passes.len() == 1 {
+                    (render_texture.texture.clone(), None)
+                } else {
+                    let targets = world.resource::<PostProcessTargets>();
+                    let textures = [render_texture.texture.clone(), targets.extra.clone()];
+                    let target = (i + 1 < passes.len()).then(|| targets.framebuffers[(i + 1) % 2]);
+                    (textures[i % 2].clone(), target)
+                };
+                let source = match (&pass.tonemap, &hdr_source) {
+                    (Some(_), Some((texture, _))) => texture.clone(),
+                    _ => source,
+                };
+                ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, target);
 
-            ctx.start_alpha_blend();
-            ctx.gl.disable(glow::DEPTH_TEST);
+                ctx.use_cached_program(shader_index);
 
-            let pos_loc = ctx.get_attrib_location(shader_index, "a_position").unwrap();
+                ctx.start_alpha_blend();
+                ctx.gl.disable(glow::DEPTH_TEST);
 
-            ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(positions_vbo));
-            ctx.gl.enable_vertex_attrib_array(pos_loc);
-            ctx.gl
-                .vertex_attrib_pointer_f32(pos_loc, 2, glow::FLOAT, false, 8, 0);
+                let pos_loc = ctx.get_attrib_location(shader_index, "a_position").unwrap();
 
-            ctx.load_tex(
-                world.resource::<GpuImages>(),
-                "render_target",
-                &Tex::Ref(render_texture.texture),
-            );
+                ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(positions_vbo));
+                ctx.gl.enable_vertex_attrib_array(pos_loc);
+                ctx.gl
+                    .vertex_attrib_pointer_f32(pos_loc, 2, glow::FLOAT, false, 8, 0);
+
+                ctx.load_tex(
+                    world.resource::<GpuImages>(),
+                    "render_target",
+                    &Tex::Ref(source),
+                );
+                pass.load_uniforms(ctx, time, texel_size);
 
-            ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+                ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+            }
 
             ctx.set_cull_mode(None);
         };
     });
 }
 
+/// Makes sure `PostProcessTargets` matches the `RenderTexture`.
+fn update_post_process_targets(
+    ctx: &mut BevyGlContext,
+    world: &mut World,
+    render_texture: &RenderTexture,
+) {
+    let (width, height) = (render_texture.width, render_texture.height);
+    if world
+        .get_resource::<PostProcessTargets>()
+        .is_some_and(|targets| targets.width == width && targets.height == height)
+    {
+        return;
+    }
+    let extra = if let Some(old) = world.remove_resource::<PostProcessTargets>() {
+        unsafe {
+            for framebuffer in old.framebuffers {
+                ctx.gl.delete_framebuffer(framebuffer);
+            }
+            if let Some((tex, _target)) = world
+                .resource_mut::<GpuImages>()
+                .texture_from_ref(&old.extra)
+            {
+                ctx.gl.delete_texture(tex);
+            }
+        }
+        old.extra
+    } else {
+        TextureRef::new()
+    };
+    let mut images = world.resource_mut::<GpuImages>();
+    RenderTexture::init(ctx, &mut images, &extra, width, height);
+    let framebuffers = [&render_texture.texture, &extra].map(|texture_ref| unsafe {
+        let framebuffer = ctx.gl.create_framebuffer().unwrap();
+        ctx.gl
+            .bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+        ctx.gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            images
+                .texture_from_ref(texture_ref)
+                .map(|(tex, _target)| tex),
+            0,
+        );
+        framebuffer
+    });
+    unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None) };
+    world.insert_resource(PostProcessTargets {
+        width,
+        height,
+        extra,
+        framebuffers,
+    });
+}
+
 pub fn copy_render_target(
     mut commands: Commands,
     bevy_window: Single<&Window>,
@@ -100,7 +443,7 @@ pub fn copy_render_target(
     render_target: Option<ResMut<RenderTexture>>,
     settings: Res<PostProcessSettings>,
 ) {
-    if !settings.enable {
+    if !settings.is_active() {
         return;
     }
     let width = bevy_window.physical_width().max(1);
@@ -141,6 +484,12 @@ pub fn copy_render_target(
         });
     }
 
+    // With `hdr` the scene is already in the `HdrTarget`, the texture is only used to ping pong.
+    if settings.hdr {
+        commands.insert_resource(render_target_texture);
+        return;
+    }
+
     let render_target = render_target_texture.clone();
     enc.record(move |ctx, world| {
         unsafe {
@@ -166,6 +515,176 @@ pub fn copy_render_target(
     commands.insert_resource(render_target_texture.clone());
 }
 
+/// What std_mat draws the main view into with `PostProcessSettings::hdr`, read by the tonemap
+/// pass. Half float where the GL can render to it, otherwise 8 bit with `hdr_encode`. Alpha is
+/// cleared to 0 so the tonemap can pass the `ClearColor` through untouched.
+#[derive(Resource)]
+pub struct HdrTarget {
+    width: u32,
+    height: u32,
+    texture: TextureRef,
+    framebuffer: glow::Framebuffer,
+    depth: glow::Renderbuffer,
+    pub float: bool,
+}
+
+impl HdrTarget {
+    /// Binds the target for drawing the main view, clearing it first if `clear_color` is given.
+    pub fn bind(&self, ctx: &mut BevyGlContext, clear_color: Option<Color>) {
+        unsafe {
+            ctx.gl
+                .bind_framebuffer(glow::FRAMEBUFFER, Some(self.framebuffer));
+            ctx.gl.viewport(0, 0, self.width as i32, self.height as i32);
+            if let Some(clear_color) = clear_color {
+                let c = clear_color.to_srgba();
+                ctx.gl.depth_mask(true);
+                ctx.gl.clear_color(c.red, c.green, c.blue, 0.0);
+                ctx.gl.clear_depth_f32(1.0);
+                ctx.gl
+                    .clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+            }
+        }
+    }
+
+    fn delete(self, ctx: &mut BevyGlContext, world: &mut World) {
+        unsafe {
+            ctx.gl.delete_framebuffer(self.framebuffer);
+            ctx.gl.delete_renderbuffer(self.depth);
+            if let Some((tex, _target)) = world
+                .resource_mut::<GpuImages>()
+                .texture_from_ref(&self.texture)
+            {
+                ctx.gl.delete_texture(tex);
+            }
+        }
+    }
+}
+
+/// Desktop GL 3 can always render to half float, GLES 3 and WebGL 2 need an extension.
+fn float_color_buffer_supported(gl: &glow::Context) -> bool {
+    let version = gl.version();
+    if version.major < 3 {
+        return false;
+    }
+    !version.is_embedded
+        || [
+            "GL_EXT_color_buffer_float",
+            "EXT_color_buffer_float",
+            "GL_EXT_color_buffer_half_float",
+            "EXT_color_buffer_half_float",
+        ]
+        .iter()
+        .any(|extension| gl.supported_extensions().contains(*extension))
+}
+
+/// Keeps the `HdrTarget` matching the window while `PostProcessSettings::hdr` is on.
+fn update_hdr_target(
+    mut enc: ResMut<CommandEncoder>,
+    bevy_window: Single<&Window>,
+    settings: Res<PostProcessSettings>,
+) {
+    let hdr = settings.hdr;
+    let width = bevy_window.physical_width().max(1);
+    let height = bevy_window.physical_height().max(1);
+    enc.record(move |ctx, world| {
+        if hdr
+            && world
+                .get_resource::<HdrTarget>()
+                .is_some_and(|target| target.width == width && target.height == height)
+        {
+            return;
+        }
+        if let Some(old) = world.remove_resource::<HdrTarget>() {
+            old.delete(ctx, world);
+        }
+        if !hdr {
+            return;
+        }
+
+        let texture_ref = TextureRef::new();
+        let mut images = world.resource_mut::<GpuImages>();
+        RenderTexture::init(ctx, &mut images, &texture_ref, width, height);
+        let texture = images.texture_from_ref(&texture_ref).map(|(tex, _)| tex);
+        unsafe {
+            let framebuffer = ctx.gl.create_framebuffer().unwrap();
+            ctx.gl
+                .bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            let depth = ctx.gl.create_renderbuffer().unwrap();
+            ctx.gl.bind_renderbuffer(glow::RENDERBUFFER, Some(depth));
+            ctx.gl.renderbuffer_storage(
+                glow::RENDERBUFFER,
+                glow::DEPTH_COMPONENT16,
+                width as i32,
+                height as i32,
+            );
+            ctx.gl.framebuffer_renderbuffer(
+                glow::FRAMEBUFFER,
+                glow::DEPTH_ATTACHMENT,
+                glow::RENDERBUFFER,
+                Some(depth),
+            );
+
+            let mut float = false;
+            if float_color_buffer_supported(&ctx.gl) {
+                ctx.gl.bind_texture(glow::TEXTURE_2D, texture);
+                ctx.gl.tex_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    glow::RGBA16F as i32,
+                    width as i32,
+                    height as i32,
+                    0,
+                    glow::RGBA,
+                    glow::HALF_FLOAT,
+                    PixelUnpackData::Slice(None),
+                );
+                ctx.gl.framebuffer_texture_2d(
+                    glow::FRAMEBUFFER,
+                    glow::COLOR_ATTACHMENT0,
+                    glow::TEXTURE_2D,
+                    texture,
+                    0,
+                );
+                float = ctx.gl.check_framebuffer_status(glow::FRAMEBUFFER)
+                    == glow::FRAMEBUFFER_COMPLETE;
+                if !float {
+                    warn!("Can't render to half float, hdr falls back to 8 bit");
+                    // Back to the 8 bit storage `RenderTexture::init` gave it.
+                    ctx.gl.tex_image_2d(
+                        glow::TEXTURE_2D,
+                        0,
+                        glow::RGBA as i32,
+                        width as i32,
+                        height as i32,
+                        0,
+                        glow::RGBA,
+                        glow::UNSIGNED_BYTE,
+                        PixelUnpackData::Slice(None),
+                    );
+                }
+            }
+            ctx.gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::COLOR_ATTACHMENT0,
+                glow::TEXTURE_2D,
+                texture,
+                0,
+            );
+            ctx.gl.bind_renderbuffer(glow::RENDERBUFFER, None);
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+            world.insert_resource(HdrTarget {
+                width,
+                height,
+                texture: texture_ref,
+                framebuffer,
+                depth,
+                float,
+            });
+        }
+    });
+}
+
 #[derive(UniformSet, Resource, Clone, Default)]
 #[uniform_set(prefix = "ub_")]
 pub struct RenderTexture {
@@ -222,3 +741,96 @@ impl RenderTexture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass_defs(settings: &PostProcessSettings) -> Vec<Vec<&'static str>> {
+        PostPass::build(settings, &TonemapSettings::default())
+            .iter()
+            .map(|pass| {
+                let defs = pass.shader_defs(false);
+                defs.into_iter().map(|(def, _)| def).collect()
+            })
+            .collect()
+    }
+
+    const VIGNETTE: PostEffectKind = PostEffectKind::Vignette {
+        strength: 0.5,
+        radius: 0.4,
+    };
+    const GRAIN: PostEffectKind = PostEffectKind::FilmGrain { strength: 0.03 };
+    const SCANLINES: PostEffectKind = PostEffectKind::Scanlines {
+        count: 240.0,
+        strength: 0.2,
+    };
+    const BLUR: PostEffectKind = PostEffectKind::Blur { radius: 1.0 };
+    const ABERRATION: PostEffectKind = PostEffectKind::ChromaticAberration { offset: 0.005 };
+
+    #[test]
+    fn pixel_effects_share_a_pass_in_order() {
+        let settings = PostProcessSettings::new([VIGNETTE, GRAIN, SCANLINES]);
+        assert_eq!(
+            pass_defs(&settings),
+            [vec!["VIGNETTE", "FILM_GRAIN", "SCANLINES"]]
+        );
+    }
+
+    #[test]
+    fn out_of_order_run_is_split() {
+        let settings = PostProcessSettings::new([SCANLINES, VIGNETTE, GRAIN, VIGNETTE]);
+        assert_eq!(
+            pass_defs(&settings),
+            [
+                vec!["SCANLINES"],
+                vec!["VIGNETTE", "FILM_GRAIN"],
+                vec!["VIGNETTE"]
+            ]
+        );
+    }
+
+    #[test]
+    fn sampled_effects_start_a_pass() {
+        let settings = PostProcessSettings::new([VIGNETTE, BLUR, GRAIN, ABERRATION, BLUR]);
+        assert_eq!(
+            pass_defs(&settings),
+            [
+                vec!["VIGNETTE"],
+                vec!["BLUR", "FILM_GRAIN"],
+                vec!["CHROMATIC_ABERRATION"],
+                vec!["BLUR"]
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_effects_are_skipped() {
+        let mut settings = PostProcessSettings::new([VIGNETTE, BLUR, GRAIN]);
+        settings.effects[1].enabled = false;
+        assert_eq!(pass_defs(&settings), [vec!["VIGNETTE", "FILM_GRAIN"]]);
+
+        for effect in &mut settings.effects {
+            effect.enabled = false;
+        }
+        assert!(pass_defs(&settings).is_empty());
+        assert!(!settings.is_active());
+    }
+
+    #[test]
+    fn tonemap_comes_first() {
+        let settings = PostProcessSettings::new([GRAIN, ABERRATION]).with_hdr(1.0);
+        assert_eq!(
+            pass_defs(&settings),
+            [
+                vec!["TONEMAP", "TONEMAP_AGX", "FILM_GRAIN"],
+                vec!["CHROMATIC_ABERRATION"]
+            ]
+        );
+        let passes = PostPass::build(&settings, &TonemapSettings::default());
+        assert_eq!(passes[0].tonemap.map(|(_, exposure)| exposure), Some(2.0));
+
+        let tonemap_only = PostProcessSettings::default().with_hdr(0.0);
+        assert_eq!(pass_defs(&tonemap_only), [vec!["TONEMAP", "TONEMAP_AGX"]]);
+    }
+}
@@ -1,6 +1,7 @@
-use std::{f32::consts::PI, ops::Deref};
+use std::f32::consts::PI;
 
-use bevy::prelude::*;
+use bevy::{prelude::*, scene::SceneInstanceReady};
+use serde::Deserialize;
 use uniform_set_derive::UniformSet;
 
 use bgl2::{
@@ -12,6 +13,8 @@ use bgl2::{
     render::{RenderPhase, RenderSet},
 };
 
+use crate::light_shadows::LightShadowAssignments;
+
 // It seems like some drivers are limited by code length.
 // The point light loop is unrolled so setting this too high can be an issue.
 // Also fragment shader uniform capacity can be very limited on some drivers.
@@ -22,15 +25,13 @@ pub const DEFAULT_MAX_LIGHTS_DEF: (&str, &str) = ("MAX_POINT_LIGHTS", "8");
 pub const DEFAULT_MAX_JOINTS: usize = 32;
 pub const DEFAULT_MAX_JOINTS_DEF: (&str, &str) = ("MAX_JOINTS", "32");
 
+// Every shaft light is sampled at every step, keep this small.
+pub const DEFAULT_MAX_SHAFT_LIGHTS: usize = 4;
+pub const DEFAULT_MAX_SHAFT_LIGHTS_DEF: (&str, &str) = ("MAX_SHAFT_LIGHTS", "4");
+
 #[derive(UniformSet, Resource, Clone, Default)]
 #[uniform_set(prefix = "ub_")]
 pub struct GameLightingUniforms {
-    #[array_max("MAX_POINT_LIGHTS")]
-    pub point_light_position_range: Vec<Vec4>,
-    #[array_max("MAX_POINT_LIGHTS")]
-    pub point_light_color_radius: Vec<Vec4>,
-    #[array_max("MAX_POINT_LIGHTS")]
-    pub spot_light_dir_offset_scale: Vec<Vec4>,
     pub directional_light_dir: Vec3,
     pub directional_light_color: Vec3,
     #[base_type("samplerCube")]
@@ -40,22 +41,49 @@ pub struct GameLightingUniforms {
     pub shadow_texture: TextureRef,
     pub env_intensity: f32,
     pub shadow_clip_from_world: Mat4,
+    /// Spot lights whose cones are ray marched through the fog, see `LightShaftSettings`.
+    #[array_max("MAX_SHAFT_LIGHTS")]
+    pub light_shaft_position_range: Vec<Vec4>,
+    #[array_max("MAX_SHAFT_LIGHTS")]
+    pub light_shaft_color_radius: Vec<Vec4>,
+    #[array_max("MAX_SHAFT_LIGHTS")]
+    pub light_shaft_dir_offset_scale: Vec<Vec4>,
+    #[array_max("MAX_SHAFT_LIGHTS")]
+    pub light_shaft_shadow: Vec<Vec4>,
+    pub light_shaft_count: i32,
+    pub light_shaft_max_distance: f32,
+    pub light_shaft_intensity: f32,
+    #[exclude]
+    pub light_shaft_quality: LightShaftQuality,
+}
+
+/// The point and spot lights used by a single draw, see `DynamicLights::select`.
+#[derive(UniformSet, Clone, Default)]
+#[uniform_set(prefix = "ub_")]
+pub struct DrawLightUniforms {
+    #[array_max("MAX_POINT_LIGHTS")]
+    pub point_light_position_range: Vec<Vec4>,
+    #[array_max("MAX_POINT_LIGHTS")]
+    pub point_light_color_radius: Vec<Vec4>,
+    #[array_max("MAX_POINT_LIGHTS")]
+    pub spot_light_dir_offset_scale: Vec<Vec4>,
+    /// See `DynamicLightData::shadow`.
+    #[array_max("MAX_POINT_LIGHTS")]
+    pub point_light_shadow: Vec<Vec4>,
     pub light_count: i32,
 }
 
 impl GameLightingUniforms {
+    /// `point` should be false if there are no point or spot lights at all.
     pub fn shader_defs(
         &self,
         point: bool,
         shadow: bool,
         phase: &RenderPhase,
-    ) -> [(&'static str, &'static str); 3] {
+    ) -> [(&'static str, &'static str); 4] {
         [
-            if !point || self.light_count == 0 {
-                ("NO_POINT", "")
-            } else {
-                ("", "")
-            },
+            DEFAULT_MAX_SHAFT_LIGHTS_DEF,
+            if !point { ("NO_POINT", "") } else { ("", "") },
             if self.specular_map.is_some() && self.diffuse_map.is_some() {
                 ("", "")
             } else {
@@ -78,79 +106,239 @@ pub struct PrepareLightingPlugin;
 impl Plugin for PrepareLightingPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<GameLightingUniforms>()
-            .add_systems(Update, prepare_standard_lighting.in_set(RenderSet::Prepare));
+            .init_resource::<DynamicLights>()
+            .init_resource::<LightShaftSettings>()
+            .add_systems(
+                Update,
+                (
+                    animate_lights.before(prepare_standard_lighting),
+                    prepare_standard_lighting.in_set(RenderSet::Prepare),
+                ),
+            );
     }
 }
 
 #[derive(Default, Component, Clone, Copy)]
 pub struct DynamicLight;
 
-fn prepare_standard_lighting(
-    point_lights: Query<(&PointLight, &GlobalTransform), With<DynamicLight>>,
-    spot_lights: Query<(&SpotLight, &GlobalTransform), With<DynamicLight>>,
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LightShaftQuality {
+    Off,
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+impl LightShaftQuality {
+    pub const ALL: [LightShaftQuality; 4] = [
+        LightShaftQuality::Off,
+        LightShaftQuality::Low,
+        LightShaftQuality::Medium,
+        LightShaftQuality::High,
+    ];
+
+    /// Ray march steps per pixel.
+    pub fn shader_def(self) -> (&'static str, &'static str) {
+        match self {
+            LightShaftQuality::Off => ("", ""),
+            LightShaftQuality::Low => ("LIGHT_SHAFT_STEPS", "8"),
+            LightShaftQuality::Medium => ("LIGHT_SHAFT_STEPS", "16"),
+            LightShaftQuality::High => ("LIGHT_SHAFT_STEPS", "32"),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LightShaftQuality::Off => "OFF",
+            LightShaftQuality::Low => "LOW",
+            LightShaftQuality::Medium => "MEDIUM",
+            LightShaftQuality::High => "HIGH",
+        }
+    }
+}
+
+/// Volumetric shafts from dynamic spot lights, scattered by the `Fog` medium. Only drawn in
+/// scenes with fog.
+#[derive(Resource, Clone)]
+pub struct LightShaftSettings {
+    pub quality: LightShaftQuality,
+    /// The view ray is marched up to this far.
+    pub max_distance: f32,
+    pub intensity: f32,
+}
+
+impl Default for LightShaftSettings {
+    fn default() -> Self {
+        LightShaftSettings {
+            quality: LightShaftQuality::default(),
+            max_distance: 120.0,
+            intensity: 1.0,
+        }
+    }
+}
+
+/// Per light settings from glTF extras (custom properties on the Blender light, or its object), e.g.
+/// `{"light intensity scale": 50, "light range": 10000, "light dynamic": 1, "light shadows": 1,
+/// "light flicker": 0.15, "light flicker speed": 6}`.
+/// Anything a light doesn't set comes from its scene's `SceneLightDefaults`.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+pub struct LightExtras {
+    /// Multiplies the imported intensity.
+    #[serde(rename = "light intensity scale")]
+    pub intensity_scale: Option<f32>,
+    #[serde(rename = "light range")]
+    pub range: Option<f32>,
+    /// Rendered in realtime as a `DynamicLight` instead of being baked into the probes.
+    #[serde(rename = "light dynamic", default, deserialize_with = "extras_flag")]
+    pub dynamic: Option<bool>,
+    #[serde(rename = "light shadows", default, deserialize_with = "extras_flag")]
+    pub shadows: Option<bool>,
+    /// Flickers down to this intensity scale with a `LightCurve::Flicker`, needs `dynamic`.
+    #[serde(rename = "light flicker")]
+    pub flicker_min: Option<f32>,
+    /// Changes per second of the flicker, 6 if not set.
+    #[serde(rename = "light flicker speed")]
+    pub flicker_speed: Option<f32>,
+}
+
+impl LightExtras {
+    fn or(self, defaults: LightExtras) -> LightExtras {
+        LightExtras {
+            intensity_scale: self.intensity_scale.or(defaults.intensity_scale),
+            range: self.range.or(defaults.range),
+            dynamic: self.dynamic.or(defaults.dynamic),
+            shadows: self.shadows.or(defaults.shadows),
+            flicker_min: self.flicker_min.or(defaults.flicker_min),
+            flicker_speed: self.flicker_speed.or(defaults.flicker_speed),
+        }
+    }
+}
+
+/// Blender exports bool custom properties as 0 or 1.
+fn extras_flag<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<bool>, D::Error> {
+    Ok(match serde_json::Value::deserialize(d)? {
+        serde_json::Value::Bool(b) => Some(b),
+        serde_json::Value::Number(n) => Some(n.as_f64() != Some(0.0)),
+        _ => None,
+    })
+}
+
+/// On a `SceneRoot` observed by `apply_light_extras`, for the lights in it without extras.
+#[derive(Component, Clone, Copy, Default, Debug)]
+pub struct SceneLightDefaults(pub LightExtras);
+
+/// Applies `LightExtras` to the point and spot lights in a scene once it's spawned. Dynamic
+/// lights get `DynamicLight` and are left out of baking, and a `LightAnimation` if they flicker.
+pub fn apply_light_extras(
+    scene_ready: On<SceneInstanceReady>,
+    mut commands: Commands,
+    children: Query<&Children>,
+    mut lights: Query<(
+        Option<&Name>,
+        Option<&mut PointLight>,
+        Option<&mut SpotLight>,
+    )>,
+    gltf_extras: Query<&GltfExtras>,
+    parents: Query<&ChildOf>,
+    scene_defaults: Query<&SceneLightDefaults>,
+) {
+    let defaults = scene_defaults
+        .get(scene_ready.entity)
+        .copied()
+        .unwrap_or_default()
+        .0;
+    for entity in children.iter_descendants(scene_ready.entity) {
+        let Ok((name, point_light, spot_light)) = lights.get_mut(entity) else {
+            continue;
+        };
+        if point_light.is_none() && spot_light.is_none() {
+            continue;
+        }
+        let name = name.map_or("unnamed", |name| name.as_str());
+        // The gltf loader spawns lights as children of their node.
+        let extras = gltf_extras.get(entity).ok().or_else(|| {
+            parents
+                .get(entity)
+                .ok()
+                .and_then(|parent| gltf_extras.get(parent.parent()).ok())
+        });
+        let settings = match extras.map(|extras| serde_json::from_str::<LightExtras>(&extras.value))
+        {
+            Some(Ok(extras)) => extras.or(defaults),
+            Some(Err(e)) => {
+                warn!("Failed to parse extras for light {name}: {e}");
+                defaults
+            }
+            None => defaults,
+        };
+        debug!("Light {name}: {settings:?}");
+
+        let scale = settings.intensity_scale.unwrap_or(1.0);
+        if let Some(mut light) = point_light {
+            light.intensity *= scale;
+            light.range = settings.range.unwrap_or(light.range);
+            light.shadows_enabled = settings.shadows.unwrap_or(light.shadows_enabled);
+        } else if let Some(mut light) = spot_light {
+            light.intensity *= scale;
+            light.range = settings.range.unwrap_or(light.range);
+            light.shadows_enabled = settings.shadows.unwrap_or(light.shadows_enabled);
+        }
+        if settings.dynamic == Some(true) {
+            let mut ecmds = commands.entity(entity);
+            ecmds.insert(DynamicLight);
+            #[cfg(feature = "asset_baking")]
+            ecmds.insert(crate::rt_scene::NoBake);
+            if let Some(min) = settings.flicker_min {
+                ecmds.insert(LightAnimation::new(LightCurve::Flicker {
+                    min,
+                    speed: settings.flicker_speed.unwrap_or(6.0),
+                }));
+            }
+        } else if settings.flicker_min.is_some() {
+            warn!(
+                "Light {name} has \"light flicker\" but isn't dynamic, baked lights can't flicker"
+            );
+        }
+    }
+}
+
+pub fn prepare_standard_lighting(
+    point_lights: Query<(Entity, &PointLight, &GlobalTransform), With<DynamicLight>>,
+    spot_lights: Query<(Entity, &SpotLight, &GlobalTransform), With<DynamicLight>>,
     directional_lights: Query<(&DirectionalLight, &GlobalTransform)>,
     shadow: Option<Res<DirectionalLightShadow>>,
-    env_light: Single<Option<&EnvironmentMapLight>, With<Camera3d>>,
+    light_shadows: Option<Res<LightShadowAssignments>>,
+    camera: Single<(&GlobalTransform, Option<&EnvironmentMapLight>), With<Camera3d>>,
+    shaft_settings: Res<LightShaftSettings>,
+    mut dynamic_lights: ResMut<DynamicLights>,
     mut enc: ResMut<CommandEncoder>,
 ) {
-    let lighting_uniform = GameLightingUniforms::new(
-        point_lights,
-        spot_lights,
+    let (camera_trans, env_light) = *camera;
+    *dynamic_lights = DynamicLights::new(&point_lights, &spot_lights, |entity| {
+        light_shadows
+            .as_ref()
+            .and_then(|shadows| shadows.shadow(entity))
+    });
+    let mut lighting_uniform = GameLightingUniforms::new(
         clone2(directional_lights.single().ok()),
-        *env_light.deref(),
+        env_light,
         shadow.as_deref(),
-        DEFAULT_MAX_POINT_LIGHTS,
     );
+    lighting_uniform.set_light_shafts(&dynamic_lights, camera_trans.translation(), &shaft_settings);
     enc.record(move |_ctx, world| {
         world.insert_resource(lighting_uniform);
     });
 }
 
 impl GameLightingUniforms {
-    pub fn new<'a, PI, SI>(
-        point_lights: PI,
-        spot_lights: SI,
+    pub fn new(
         directional_light: Option<(DirectionalLight, GlobalTransform)>,
         env_light: Option<&EnvironmentMapLight>,
         shadow: Option<&DirectionalLightShadow>,
-        max_point_spot: usize,
-    ) -> Self
-    where
-        PI: IntoIterator<Item = (&'a PointLight, &'a GlobalTransform)>,
-        SI: IntoIterator<Item = (&'a SpotLight, &'a GlobalTransform)>,
-    {
+    ) -> Self {
         let mut data = GameLightingUniforms::default();
 
-        for (light, trans) in point_lights {
-            if data.point_light_position_range.len() >= max_point_spot {
-                break;
-            }
-            data.point_light_position_range
-                .push(trans.translation().extend(light.range));
-            data.point_light_color_radius.push(
-                (light.color.to_linear().to_vec3() * light.intensity * POWER_TO_INTENSITY)
-                    .extend(light.radius),
-            );
-            data.spot_light_dir_offset_scale
-                .push(vec4(1.0, 0.0, 2.0, 1.0));
-        }
-
-        for (light, trans) in spot_lights {
-            if data.point_light_position_range.len() >= max_point_spot {
-                break;
-            }
-            data.point_light_position_range
-                .push(trans.translation().extend(light.range));
-            data.point_light_color_radius.push(
-                (light.color.to_linear().to_vec3() * light.intensity * POWER_TO_INTENSITY)
-                    .extend(light.radius),
-            );
-            data.spot_light_dir_offset_scale
-                .push(calc_spot_dir_offset_scale(light, trans));
-        }
-
-        data.light_count = data.point_light_position_range.len() as i32;
-
         if let Some((light, trans)) = directional_light {
             data.directional_light_dir = trans.forward().as_vec3();
             data.directional_light_color = light.color.to_linear().to_vec3() * light.illuminance;
@@ -169,6 +357,150 @@ impl GameLightingUniforms {
 
         data
     }
+
+    /// Picks the spot lights with the most influence around the camera for the shafts.
+    pub fn set_light_shafts(
+        &mut self,
+        lights: &DynamicLights,
+        camera_position: Vec3,
+        settings: &LightShaftSettings,
+    ) {
+        let reach = Vec3A::splat(settings.max_distance);
+        let aabb = obvhs::aabb::Aabb::new(
+            camera_position.to_vec3a() - reach,
+            camera_position.to_vec3a() + reach,
+        );
+        let mut scored: Vec<(f32, &DynamicLightData)> = lights
+            .0
+            .iter()
+            .filter(|light| light.spot)
+            .map(|light| (light_influence(light, &aabb), light))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(DEFAULT_MAX_SHAFT_LIGHTS);
+        if settings.quality == LightShaftQuality::Off {
+            scored.clear();
+        }
+
+        let shafts = scored.iter().map(|(_, light)| light);
+        self.light_shaft_position_range = shafts.clone().map(|l| l.position_range).collect();
+        self.light_shaft_color_radius = shafts.clone().map(|l| l.color_radius).collect();
+        self.light_shaft_dir_offset_scale =
+            shafts.clone().map(|l| l.spot_dir_offset_scale).collect();
+        self.light_shaft_shadow = shafts.map(|l| l.shadow).collect();
+        self.light_shaft_count = scored.len() as i32;
+        self.light_shaft_max_distance = settings.max_distance;
+        self.light_shaft_intensity = settings.intensity;
+        self.light_shaft_quality = settings.quality;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DynamicLightData {
+    pub position_range: Vec4,
+    pub color_radius: Vec4,
+    pub spot_dir_offset_scale: Vec4,
+    /// From `LightShadowAssignments::shadow`, x is -1.0 if the light has no shadow.
+    pub shadow: Vec4,
+    pub spot: bool,
+}
+
+/// All point and spot lights with `DynamicLight`, ordered by entity so selection doesn't depend
+/// on query order. Each draw only gets the `DEFAULT_MAX_POINT_LIGHTS` that affect it the most.
+#[derive(Resource, Clone, Default)]
+pub struct DynamicLights(pub Vec<DynamicLightData>);
+
+impl DynamicLights {
+    pub fn new<'a, PI, SI>(
+        point_lights: PI,
+        spot_lights: SI,
+        shadow: impl Fn(Entity) -> Option<Vec4>,
+    ) -> Self
+    where
+        PI: IntoIterator<Item = (Entity, &'a PointLight, &'a GlobalTransform)>,
+        SI: IntoIterator<Item = (Entity, &'a SpotLight, &'a GlobalTransform)>,
+    {
+        let shadow = |entity| shadow(entity).unwrap_or(vec4(-1.0, 0.0, 0.0, 0.0));
+        let point = point_lights.into_iter().map(|(entity, light, trans)| {
+            (
+                entity,
+                DynamicLightData {
+                    position_range: trans.translation().extend(light.range),
+                    color_radius: (light.color.to_linear().to_vec3()
+                        * light.intensity
+                        * POWER_TO_INTENSITY)
+                        .extend(light.radius),
+                    spot_dir_offset_scale: vec4(1.0, 0.0, 2.0, 1.0),
+                    shadow: shadow(entity),
+                    spot: false,
+                },
+            )
+        });
+        let spot = spot_lights.into_iter().map(|(entity, light, trans)| {
+            (
+                entity,
+                DynamicLightData {
+                    position_range: trans.translation().extend(light.range),
+                    color_radius: (light.color.to_linear().to_vec3()
+                        * light.intensity
+                        * POWER_TO_INTENSITY)
+                        .extend(light.radius),
+                    spot_dir_offset_scale: calc_spot_dir_offset_scale(light, trans),
+                    shadow: shadow(entity),
+                    spot: true,
+                },
+            )
+        });
+        let mut lights: Vec<_> = point.chain(spot).collect();
+        lights.sort_by_key(|(entity, _)| *entity);
+        DynamicLights(lights.into_iter().map(|(_, light)| light).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Indices of the `max` lights with the most influence on the world space `aabb`, strongest
+    /// first. Lights whose range doesn't reach the aabb are left out.
+    pub fn select(&self, aabb: &obvhs::aabb::Aabb, max: usize) -> Vec<u32> {
+        let mut scored: Vec<(f32, u32)> = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, light)| (light_influence(light, aabb), i as u32))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        scored.truncate(max);
+        scored.into_iter().map(|(_, i)| i).collect()
+    }
+
+    pub fn uniforms(&self, indices: &[u32]) -> DrawLightUniforms {
+        let lights = indices.iter().map(|i| &self.0[*i as usize]);
+        DrawLightUniforms {
+            point_light_position_range: lights.clone().map(|l| l.position_range).collect(),
+            point_light_color_radius: lights.clone().map(|l| l.color_radius).collect(),
+            spot_light_dir_offset_scale: lights.clone().map(|l| l.spot_dir_offset_scale).collect(),
+            point_light_shadow: lights.map(|l| l.shadow).collect(),
+            light_count: indices.len() as i32,
+        }
+    }
+}
+
+/// Luminous intensity reaching the closest point of the aabb, with the same range falloff as
+/// bevy's point lights.
+fn light_influence(light: &DynamicLightData, aabb: &obvhs::aabb::Aabb) -> f32 {
+    let position = light.position_range.xyz().to_vec3a();
+    let range = light.position_range.w;
+    let distance = position.clamp(aabb.min, aabb.max).distance(position);
+    if distance >= range {
+        return 0.0;
+    }
+    let falloff = (1.0 - (distance / range).powi(4)).clamp(0.0, 1.0).powi(2);
+    let radius = light.color_radius.w.max(0.1);
+    let intensity = light.color_radius.xyz().dot(vec3(0.2126, 0.7152, 0.0722));
+    intensity * falloff / (distance * distance).max(radius * radius)
 }
 
 pub fn calc_spot_dir_offset_scale(light: &SpotLight, trans: &GlobalTransform) -> Vec4 {
@@ -184,3 +516,198 @@ pub fn calc_spot_dir_offset_scale(light: &SpotLight, trans: &GlobalTransform) ->
 // Map from luminous power in lumens to luminous intensity in lumens per steradian for a point light.
 // For details see: https://google.github.io/filament/Filament.html#mjx-eqn-pointLightLuminousPower
 const POWER_TO_INTENSITY: f32 = 1.0 / (4.0 * PI);
+
+/// Modulates the intensity and color of a `PointLight` or `SpotLight` over time, relative to
+/// the values it had the first time it was animated (so after any load observer scaling). Only
+/// visible on `DynamicLight`s, baked lights keep whatever they had when baked.
+#[derive(Component, Clone)]
+pub struct LightAnimation {
+    pub curve: LightCurve,
+    /// Lights in the same group share their phase and noise, so they flicker or pulse together.
+    /// Otherwise each light gets its own offset.
+    pub sync_group: Option<u32>,
+    pub linked_emissive: Option<LinkedEmissive>,
+}
+
+impl LightAnimation {
+    pub fn new(curve: LightCurve) -> Self {
+        LightAnimation {
+            curve,
+            sync_group: None,
+            linked_emissive: None,
+        }
+    }
+
+    pub fn with_sync_group(mut self, group: u32) -> Self {
+        self.sync_group = Some(group);
+        self
+    }
+
+    pub fn with_linked_emissive(mut self, linked_emissive: LinkedEmissive) -> Self {
+        self.linked_emissive = Some(linked_emissive);
+        self
+    }
+}
+
+#[derive(Clone)]
+pub enum LightCurve {
+    /// Smoothed value noise between `min` and 1, changing `speed` times per second.
+    Flicker { min: f32, speed: f32 },
+    /// Sine between `min` and 1.
+    Pulse { min: f32, period: f32 },
+    /// Linearly interpolated keys, looping after the last one.
+    Keyframes(Vec<LightKeyframe>),
+}
+
+#[derive(Clone, Copy)]
+pub struct LightKeyframe {
+    pub time: f32,
+    pub intensity: f32,
+    /// Multiplied with the light's color.
+    pub color: Color,
+}
+
+/// Swaps the material of a mesh while the light is dimmed, e.g. a bulb going dark.
+#[derive(Clone)]
+pub struct LinkedEmissive {
+    pub mesh: Entity,
+    pub off_material: Handle<StandardMaterial>,
+    /// `off_material` is used while the intensity scale is below this.
+    pub threshold: f32,
+}
+
+/// The light's values before animation.
+#[derive(Component)]
+pub struct LightAnimationBase {
+    intensity: f32,
+    color: Color,
+    on_material: Option<Handle<StandardMaterial>>,
+}
+
+impl LightCurve {
+    /// Intensity scale and linear color multiplier at `t`.
+    pub fn sample(&self, t: f32, seed: u32) -> (f32, Vec3) {
+        match self {
+            LightCurve::Flicker { min, speed } => {
+                let x = t * speed;
+                let i = x.floor() as u32;
+                let a = hash_noise(seed, i);
+                let b = hash_noise(seed, i.wrapping_add(1));
+                let f = x.fract();
+                let noise = a + (b - a) * f * f * (3.0 - 2.0 * f);
+                (min + (1.0 - min) * noise, Vec3::ONE)
+            }
+            LightCurve::Pulse { min, period } => {
+                let s = (t / period.max(1e-3) * std::f32::consts::TAU).sin() * 0.5 + 0.5;
+                (min + (1.0 - min) * s, Vec3::ONE)
+            }
+            LightCurve::Keyframes(keys) => {
+                let (Some(first), Some(last)) = (keys.first(), keys.last()) else {
+                    return (1.0, Vec3::ONE);
+                };
+                let t = if last.time > 0.0 {
+                    t.rem_euclid(last.time)
+                } else {
+                    0.0
+                };
+                let next = keys
+                    .iter()
+                    .position(|k| k.time > t)
+                    .unwrap_or(keys.len() - 1);
+                let prev = &keys[next.saturating_sub(1)];
+                let next = &keys[next];
+                let f = if next.time > prev.time {
+                    ((t - prev.time) / (next.time - prev.time)).clamp(0.0, 1.0)
+                } else if t < first.time {
+                    0.0
+                } else {
+                    1.0
+                };
+                (
+                    prev.intensity + (next.intensity - prev.intensity) * f,
+                    prev.color
+                        .to_linear()
+                        .to_vec3()
+                        .lerp(next.color.to_linear().to_vec3(), f),
+                )
+            }
+        }
+    }
+}
+
+fn hash_noise(seed: u32, i: u32) -> f32 {
+    let mut h = seed.wrapping_mul(0x9E37_79B9) ^ i.wrapping_mul(0x85EB_CA6B);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B_3C6D);
+    h ^= h >> 12;
+    (h & 0xFFFF) as f32 / 65535.0
+}
+
+fn animate_lights(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut lights: Query<(
+        Entity,
+        &LightAnimation,
+        Option<&LightAnimationBase>,
+        Option<&mut PointLight>,
+        Option<&mut SpotLight>,
+    )>,
+    mut mesh_materials: Query<&mut MeshMaterial3d<StandardMaterial>>,
+) {
+    let t = time.elapsed_secs();
+    for (entity, animation, base, point_light, spot_light) in &mut lights {
+        let Some(base) = base else {
+            let (intensity, color) = match (&point_light, &spot_light) {
+                (Some(light), _) => (light.intensity, light.color),
+                (_, Some(light)) => (light.intensity, light.color),
+                _ => continue,
+            };
+            let on_material = animation
+                .linked_emissive
+                .as_ref()
+                .and_then(|linked| mesh_materials.get(linked.mesh).ok())
+                .map(|material| material.0.clone());
+            commands.entity(entity).insert(LightAnimationBase {
+                intensity,
+                color,
+                on_material,
+            });
+            continue;
+        };
+
+        let (seed, offset) = match animation.sync_group {
+            Some(group) => (group, 0.0),
+            None => {
+                let seed = entity.to_bits() as u32;
+                (seed, hash_noise(seed, 0) * 100.0)
+            }
+        };
+        let (scale, tint) = animation.curve.sample(t + offset, seed);
+        let intensity = base.intensity * scale;
+        let color = Color::from(LinearRgba::from_vec3(
+            base.color.to_linear().to_vec3() * tint,
+        ));
+        if let Some(mut light) = point_light {
+            light.intensity = intensity;
+            light.color = color;
+        }
+        if let Some(mut light) = spot_light {
+            light.intensity = intensity;
+            light.color = color;
+        }
+
+        if let (Some(linked), Some(on_material)) = (&animation.linked_emissive, &base.on_material)
+            && let Ok(mut material) = mesh_materials.get_mut(linked.mesh)
+        {
+            let target = if scale < linked.threshold {
+                &linked.off_material
+            } else {
+                on_material
+            };
+            if material.0 != *target {
+                material.0 = target.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! CPU probe baker. Path traces the irradiance of every probe of a `CascadeInput` and writes the
+//! textures `CascadeUniform` samples:
+//! - `probes_id` is `res.x` by `res.y * res.z`. `.xy * 255` is the probe's tile in `probes_gi` and
+//!   `.z` is the directional light visibility at the probe.
+//! - `probes_gi` is a `GI_ATLAS_SIZE` square RGBE atlas of octahedral irradiance tiles, each
+//!   `PROBE_SIZE` texels with a 1 texel border for bilinear filtering.
+//!
+//! Buried and outlier probes are repaired by `probe_dilate` before the textures are written.
+
+use std::{
+    f32::consts::PI,
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use image::{ImageFormat, Rgba, RgbaImage};
+use obvhs::ray::Ray;
+use rayon::prelude::*;
+
+use crate::{
+    bake_hash::{cascade_bake_hash, read_bake_hash, write_bake_hash},
+    cascade::{CascadeInput, PROBE_IRRADIANCE_SCALE, oct_decode, probes_gi_path, probes_id_path},
+    cascade_bake::{CascadeBakeHeader, cascade_bake_path},
+    probe_dilate::{ProbeRepairSettings, repair_probes},
+    rt_scene::{RtLightKind, RtRng, RtScene, RtSceneSources, uniform_sphere},
+};
+
+pub const PROBE_SIZE: u32 = 6;
+pub const GI_ATLAS_SIZE: u32 = 2048;
+const PROBE_PAD_SIZE: u32 = PROBE_SIZE + 2;
+const TILES_PER_ROW: u32 = GI_ATLAS_SIZE / PROBE_PAD_SIZE;
+/// Rays per probe also checked for backface hits, see `ProbeRepairSettings`.
+const VALIDITY_RAYS: usize = 128;
+
+#[derive(Resource, Default)]
+pub struct ProbeBakerPlugin;
+
+impl Plugin for ProbeBakerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ProbeBakeSettings>()
+            .init_resource::<ProbeBakeReport>()
+            .add_systems(Update, bake_probes);
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct ProbeBakeSettings {
+    pub rays_per_probe: u32,
+    pub bounces: u32,
+    /// Skip cascades whose inputs hash matches the one stored with their last bake, see
+    /// `bake_hash`.
+    pub incremental: bool,
+    pub repair: ProbeRepairSettings,
+    /// The bake files are written to `bake/` in here.
+    pub assets_dir: PathBuf,
+}
+
+impl Default for ProbeBakeSettings {
+    fn default() -> Self {
+        ProbeBakeSettings {
+            rays_per_probe: 1024,
+            bounces: 2,
+            incremental: true,
+            repair: ProbeRepairSettings::default(),
+            assets_dir: PathBuf::from("assets"),
+        }
+    }
+}
+
+/// Bakes this cascade on the next update.
+#[derive(Component)]
+pub struct NeedsProbeBake;
+
+/// Results of the last `bake_probes` run.
+#[derive(Resource, Default)]
+pub struct ProbeBakeReport {
+    pub results: Vec<CascadeBakeResult>,
+}
+
+pub struct CascadeBakeResult {
+    pub name: String,
+    pub probes: u32,
+    pub duration: Duration,
+    pub outcome: CascadeBakeOutcome,
+}
+
+pub enum CascadeBakeOutcome {
+    Baked(RebakeReason),
+    /// Skipped, the inputs hash matched the previous bake.
+    Unchanged,
+    Failed(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RebakeReason {
+    NoPreviousBake,
+    InputsChanged,
+    /// `ProbeBakeSettings::incremental` is off.
+    Forced,
+}
+
+impl std::fmt::Display for RebakeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RebakeReason::NoPreviousBake => write!(f, "no previous bake"),
+            RebakeReason::InputsChanged => write!(f, "inputs changed"),
+            RebakeReason::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+pub struct BakedCascade {
+    pub header: CascadeBakeHeader,
+    pub probes_gi: RgbaImage,
+    pub probes_id: RgbaImage,
+}
+
+#[derive(Debug)]
+pub enum ProbeBakeError {
+    TooManyProbes(u32),
+    Io(std::io::Error),
+    Image(image::ImageError),
+}
+
+impl std::fmt::Display for ProbeBakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeBakeError::TooManyProbes(count) => write!(
+                f,
+                "{count} probes don't fit in the gi atlas, max is {}",
+                TILES_PER_ROW * TILES_PER_ROW
+            ),
+            ProbeBakeError::Io(e) => write!(f, "io error: {e}"),
+            ProbeBakeError::Image(e) => write!(f, "image error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeBakeError {}
+
+impl From<std::io::Error> for ProbeBakeError {
+    fn from(e: std::io::Error) -> Self {
+        ProbeBakeError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for ProbeBakeError {
+    fn from(e: image::ImageError) -> Self {
+        ProbeBakeError::Image(e)
+    }
+}
+
+pub fn bake_probes(
+    mut commands: Commands,
+    cascades: Query<(Entity, &CascadeInput), With<NeedsProbeBake>>,
+    sources: RtSceneSources,
+    settings: Res<ProbeBakeSettings>,
+    asset_server: Res<AssetServer>,
+    mut report: ResMut<ProbeBakeReport>,
+) {
+    if cascades.is_empty() {
+        return;
+    }
+    report.results.clear();
+    let start = Instant::now();
+    let scene = sources.build();
+    info!(
+        "Built bake scene with {} triangles in {:.2}s",
+        scene.triangles.len(),
+        start.elapsed().as_secs_f32()
+    );
+    for (entity, input) in &cascades {
+        commands.entity(entity).remove::<NeedsProbeBake>();
+        let start = Instant::now();
+        let hash = cascade_bake_hash(&scene, input, &settings);
+        let has_bake = settings
+            .assets_dir
+            .join(cascade_bake_path(&input.name))
+            .exists();
+        let reason = match read_bake_hash(&settings.assets_dir, &input.name) {
+            _ if !settings.incremental => RebakeReason::Forced,
+            _ if !has_bake => RebakeReason::NoPreviousBake,
+            None => RebakeReason::NoPreviousBake,
+            Some(previous) if previous != hash => RebakeReason::InputsChanged,
+            Some(_) => {
+                info!("Cascade {} is unchanged, skipping", input.name);
+                report.results.push(CascadeBakeResult {
+                    name: input.name.clone(),
+                    probes: 0,
+                    duration: start.elapsed(),
+                    outcome: CascadeBakeOutcome::Unchanged,
+                });
+                continue;
+            }
+        };
+
+        let result = bake_cascade(&scene, input, &settings).and_then(|baked| {
+            write_baked_cascade(&input.name, &baked, &settings.assets_dir)?;
+            write_bake_hash(&settings.assets_dir, &input.name, hash)?;
+            let res = baked.header.cascade_res;
+            Ok(res.x * res.y * res.z)
+        });
+        let duration = start.elapsed();
+        let (probes, outcome) = match result {
+            Ok(probes) => {
+                info!(
+                    "Baked cascade {} ({reason}) in {:.2}s",
+                    input.name,
+                    duration.as_secs_f32()
+                );
+                // Picked up by reload_cascade_bakes.
+                asset_server.reload(cascade_bake_path(&input.name));
+                (probes, CascadeBakeOutcome::Baked(reason))
+            }
+            Err(e) => {
+                error!("Failed to bake cascade {}: {e}", input.name);
+                (0, CascadeBakeOutcome::Failed(e.to_string()))
+            }
+        };
+        report.results.push(CascadeBakeResult {
+            name: input.name.clone(),
+            probes,
+            duration,
+            outcome,
+        });
+    }
+}
+
+pub fn bake_cascade(
+    scene: &RtScene,
+    input: &CascadeInput,
+    settings: &ProbeBakeSettings,
+) -> Result<BakedCascade, ProbeBakeError> {
+    let header =
+        CascadeBakeHeader::from_input(input, PROBE_SIZE as f32, 1.0 / GI_ATLAS_SIZE as f32);
+    let res = header.cascade_res;
+    let probe_count = res.x * res.y * res.z;
+    if probe_count > TILES_PER_ROW * TILES_PER_ROW {
+        return Err(ProbeBakeError::TooManyProbes(probe_count));
+    }
+
+    let world_from_cascade = Mat4::from_quat(input.rotation);
+    let seed = input
+        .name
+        .bytes()
+        .fold(0u64, |h, b| h.wrapping_mul(31).wrapping_add(b as u64));
+    let mut probes: Vec<ProbeTexels> = (0..probe_count)
+        .into_par_iter()
+        .map(|k| {
+            let probe = uvec3(k % res.x, (k / res.x) % res.y, k / (res.x * res.y));
+            let position = world_from_cascade.transform_point3a(
+                input.ws_aabb.min + probe.as_vec3().to_vec3a() * input.resolution,
+            );
+            let mut rng = RtRng::new(seed ^ (k as u64 + 1));
+            bake_probe(scene, position, settings, &mut rng)
+        })
+        .collect();
+
+    let stats = repair_probes(&mut probes, res, &settings.repair);
+    info!(
+        "Cascade {}: {} of {probe_count} probes buried, {} left unfilled, {} outliers clamped",
+        input.name, stats.buried, stats.unfilled, stats.clamped
+    );
+
+    let mut probes_id = RgbaImage::new(res.x, res.y * res.z);
+    let mut probes_gi = RgbaImage::new(GI_ATLAS_SIZE, GI_ATLAS_SIZE);
+    for (k, probe) in probes.iter().enumerate() {
+        let k = k as u32;
+        let tile = uvec2(k % TILES_PER_ROW, k / TILES_PER_ROW);
+        let (x, y, z) = (k % res.x, (k / res.x) % res.y, k / (res.x * res.y));
+        probes_id.put_pixel(
+            x,
+            y + z * res.y,
+            Rgba([
+                tile.x as u8,
+                tile.y as u8,
+                (probe.dir_shadow * 255.0).round() as u8,
+                255,
+            ]),
+        );
+        write_probe_tile(&mut probes_gi, tile * PROBE_PAD_SIZE, &probe.texels);
+    }
+
+    Ok(BakedCascade {
+        header,
+        probes_gi,
+        probes_id,
+    })
+}
+
+pub struct ProbeTexels {
+    /// Irradiance / pi for each octahedral texel, in probe texture units.
+    pub texels: Vec<Vec3A>,
+    pub dir_shadow: f32,
+    /// Fraction of the validity rays that hit a backface.
+    pub backface_ratio: f32,
+}
+
+fn bake_probe(
+    scene: &RtScene,
+    position: Vec3A,
+    settings: &ProbeBakeSettings,
+    rng: &mut RtRng,
+) -> ProbeTexels {
+    let samples: Vec<(Vec3A, Vec3A)> = (0..settings.rays_per_probe)
+        .map(|_| {
+            let dir = uniform_sphere(rng);
+            (
+                dir,
+                scene.radiance(Ray::new_inf(position, dir), settings.bounces, rng),
+            )
+        })
+        .collect();
+
+    // Lights can't be hit by rays, so the ones that aren't rendered in realtime are added directly.
+    let direct: Vec<(Vec3A, Vec3A)> = scene
+        .lights
+        .iter()
+        .filter(|light| !light.realtime)
+        .filter_map(|light| scene.light_illuminance(light, position))
+        .collect();
+
+    let dir_shadow = scene
+        .lights
+        .iter()
+        .find_map(|light| match light.kind {
+            RtLightKind::Directional { direction } => {
+                Some(!scene.occluded(position, direction, f32::INFINITY))
+            }
+            _ => None,
+        })
+        .map_or(1.0, |visible| visible as u32 as f32);
+
+    let validity_rays = samples.len().min(VALIDITY_RAYS);
+    let backfaces = samples[..validity_rays]
+        .iter()
+        .filter(|(dir, _)| {
+            scene
+                .intersect(&Ray::new_inf(position, *dir), f32::INFINITY)
+                .is_some_and(|hit| hit.backface)
+        })
+        .count();
+    let backface_ratio = backfaces as f32 / validity_rays.max(1) as f32;
+
+    let sample_weight = 4.0 / samples.len().max(1) as f32;
+    let texels = (0..PROBE_SIZE * PROBE_SIZE)
+        .map(|i| {
+            let uv =
+                (vec2((i % PROBE_SIZE) as f32, (i / PROBE_SIZE) as f32) + 0.5) / PROBE_SIZE as f32;
+            let n: Vec3A = oct_decode(uv * 2.0 - 1.0).into();
+            // For uniform sphere samples E/pi = 4/N * sum(L * cos).
+            let indirect: Vec3A = samples
+                .iter()
+                .map(|(dir, l)| *l * n.dot(*dir).max(0.0))
+                .sum::<Vec3A>()
+                * sample_weight;
+            let direct: Vec3A = direct
+                .iter()
+                .map(|(dir, e)| *e * n.dot(*dir).max(0.0))
+                .sum::<Vec3A>()
+                / PI;
+            (indirect + direct) / PROBE_IRRADIANCE_SCALE
+        })
+        .collect();
+
+    ProbeTexels {
+        texels,
+        dir_shadow,
+        backface_ratio,
+    }
+}
+
+/// Writes the tile and its border. The border repeats the texels across the octahedral seams so
+/// bilinear filtering at the tile edge matches the neighbouring direction.
+fn write_probe_tile(atlas: &mut RgbaImage, origin: UVec2, texels: &[Vec3A]) {
+    let size = PROBE_SIZE as i32;
+    for y in -1..=size {
+        for x in -1..=size {
+            let (cx, cy) = (x.clamp(0, size - 1), y.clamp(0, size - 1));
+            let (sx, sy) = match (cx != x, cy != y) {
+                (true, true) => (size - 1 - cx, size - 1 - cy),
+                (true, false) => (cx, size - 1 - cy),
+                (false, true) => (size - 1 - cx, cy),
+                (false, false) => (x, y),
+            };
+            let value = texels[(sx + sy * size) as usize];
+            atlas.put_pixel(
+                origin.x + (x + 1) as u32,
+                origin.y + (y + 1) as u32,
+                Rgba(rgb_to_rgbe(value.into())),
+            );
+        }
+    }
+}
+
+/// Inverse of `rgbe_to_rgb`.
+pub fn rgb_to_rgbe(rgb: Vec3) -> [u8; 4] {
+    let max = rgb.max_element();
+    if max < 1e-30 {
+        return [0; 4];
+    }
+    let exponent = max.log2().floor().clamp(-128.0, 126.0) + 1.0;
+    let scaled = rgb / exponent.exp2() * 255.0;
+    [
+        scaled.x.round().min(255.0) as u8,
+        scaled.y.round().min(255.0) as u8,
+        scaled.z.round().min(255.0) as u8,
+        (exponent + 128.0) as u8,
+    ]
+}
+
+/// Writes the png pair and the packed bake into `assets_dir`.
+pub fn write_baked_cascade(
+    name: &str,
+    baked: &BakedCascade,
+    assets_dir: &Path,
+) -> Result<(), ProbeBakeError> {
+    std::fs::create_dir_all(assets_dir.join("bake"))?;
+    let encode = |image: &RgbaImage| -> Result<Vec<u8>, ProbeBakeError> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+        Ok(bytes)
+    };
+    let gi_png = encode(&baked.probes_gi)?;
+    let id_png = encode(&baked.probes_id)?;
+    std::fs::write(assets_dir.join(probes_gi_path(name)), &gi_png)?;
+    std::fs::write(assets_dir.join(probes_id_path(name)), &id_png)?;
+    std::fs::write(
+        assets_dir.join(cascade_bake_path(name)),
+        baked.header.write(&gi_png, &id_png),
+    )?;
+    Ok(())
+}
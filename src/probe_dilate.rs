@@ -0,0 +1,192 @@
+//! Repair pass run on a cascade's probes after tracing, before they're written. Probes buried in
+//! geometry mostly see backfaces and leak the lighting from the far side of thin walls, so they
+//! are replaced with the average of their valid neighbours. Probes much brighter than their
+//! neighbourhood, usually a few rays that found a small bright emitter, are scaled down.
+
+use bevy::prelude::*;
+
+use crate::probe_baker::ProbeTexels;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeRepairSettings {
+    /// Probes with more than this fraction of their rays hitting backfaces are buried.
+    pub buried_backface_ratio: f32,
+    /// How many probes away a buried probe can be filled from.
+    pub dilation_passes: u32,
+    /// Probes brighter than this multiple of the median of their valid neighbours are scaled
+    /// down to it.
+    pub outlier_ratio: f32,
+}
+
+impl Default for ProbeRepairSettings {
+    fn default() -> Self {
+        ProbeRepairSettings {
+            buried_backface_ratio: 0.25,
+            dilation_passes: 3,
+            outlier_ratio: 4.0,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ProbeRepairStats {
+    pub buried: u32,
+    /// Buried probes with no valid probe within `dilation_passes`, left as traced.
+    pub unfilled: u32,
+    pub clamped: u32,
+}
+
+/// `probes` is indexed `x + res.x * (y + res.y * z)`.
+pub fn repair_probes(
+    probes: &mut [ProbeTexels],
+    res: UVec3,
+    settings: &ProbeRepairSettings,
+) -> ProbeRepairStats {
+    let mut stats = ProbeRepairStats::default();
+    let mut valid: Vec<bool> = probes
+        .iter()
+        .map(|p| p.backface_ratio <= settings.buried_backface_ratio)
+        .collect();
+    stats.buried = valid.iter().filter(|v| !**v).count() as u32;
+
+    // Clamp before dilating so buried probes aren't filled from outliers.
+    let energy: Vec<f32> = probes.iter().map(probe_energy).collect();
+    for (k, probe) in probes.iter_mut().enumerate() {
+        if !valid[k] {
+            continue;
+        }
+        let mut neighbours: Vec<f32> = lattice_neighbours(k, res)
+            .filter(|n| valid[*n])
+            .map(|n| energy[n])
+            .collect();
+        if neighbours.is_empty() {
+            continue;
+        }
+        neighbours.sort_by(f32::total_cmp);
+        let limit = neighbours[neighbours.len() / 2] * settings.outlier_ratio;
+        if limit > 0.0 && energy[k] > limit {
+            let scale = limit / energy[k];
+            probe.texels.iter_mut().for_each(|t| *t *= scale);
+            stats.clamped += 1;
+        }
+    }
+
+    for _ in 0..settings.dilation_passes {
+        let filled: Vec<(usize, Vec<Vec3A>, f32)> = (0..probes.len())
+            .filter(|k| !valid[*k])
+            .filter_map(|k| {
+                let neighbours: Vec<usize> =
+                    lattice_neighbours(k, res).filter(|n| valid[*n]).collect();
+                if neighbours.is_empty() {
+                    return None;
+                }
+                let weight = 1.0 / neighbours.len() as f32;
+                let mut texels = vec![Vec3A::ZERO; probes[k].texels.len()];
+                let mut dir_shadow = 0.0;
+                for n in neighbours {
+                    for (t, nt) in texels.iter_mut().zip(&probes[n].texels) {
+                        *t += *nt * weight;
+                    }
+                    dir_shadow += probes[n].dir_shadow * weight;
+                }
+                Some((k, texels, dir_shadow))
+            })
+            .collect();
+        if filled.is_empty() {
+            break;
+        }
+        for (k, texels, dir_shadow) in filled {
+            probes[k].texels = texels;
+            probes[k].dir_shadow = dir_shadow;
+            valid[k] = true;
+        }
+    }
+    stats.unfilled = valid.iter().filter(|v| !**v).count() as u32;
+    stats
+}
+
+/// Mean luminance of the probe's texels.
+fn probe_energy(probe: &ProbeTexels) -> f32 {
+    let sum: Vec3A = probe.texels.iter().copied().sum();
+    sum.dot(vec3a(0.2126, 0.7152, 0.0722)) / probe.texels.len().max(1) as f32
+}
+
+/// The up to 26 probes around probe `k`.
+fn lattice_neighbours(k: usize, res: UVec3) -> impl Iterator<Item = usize> {
+    let k = k as u32;
+    let p = ivec3(
+        (k % res.x) as i32,
+        ((k / res.x) % res.y) as i32,
+        (k / (res.x * res.y)) as i32,
+    );
+    let res = res.as_ivec3();
+    (0..27)
+        .filter(|i| *i != 13)
+        .map(move |i| p + ivec3(i % 3 - 1, (i / 3) % 3 - 1, i / 9 - 1))
+        .filter(move |n| n.cmpge(IVec3::ZERO).all() && n.cmplt(res).all())
+        .map(move |n| (n.x + res.x * (n.y + res.y * n.z)) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(value: f32, dir_shadow: f32, backface_ratio: f32) -> ProbeTexels {
+        ProbeTexels {
+            texels: vec![Vec3A::splat(value); 4],
+            dir_shadow,
+            backface_ratio,
+        }
+    }
+
+    #[test]
+    fn repair_synthetic_lattice() {
+        let res = uvec3(3, 3, 3);
+        let mut probes: Vec<ProbeTexels> = (0..27).map(|_| probe(1.0, 0.5, 0.0)).collect();
+        // Buried in the middle, leaking light from the other side of a wall.
+        let buried = 13;
+        probes[buried] = probe(50.0, 0.0, 0.8);
+        // A corner that caught a small bright emitter.
+        let outlier = 0;
+        probes[outlier] = probe(100.0, 0.5, 0.0);
+
+        let stats = repair_probes(&mut probes, res, &ProbeRepairSettings::default());
+        assert_eq!(stats.buried, 1);
+        assert_eq!(stats.clamped, 1);
+        assert_eq!(stats.unfilled, 0);
+
+        // Clamped to 4x the median of its neighbours.
+        for t in &probes[outlier].texels {
+            assert!(t.abs_diff_eq(Vec3A::splat(4.0), 1e-4), "{t}");
+        }
+        // The average of all 26 neighbours, after the outlier was clamped.
+        let filled = (25.0 + 4.0) / 26.0;
+        for t in &probes[buried].texels {
+            assert!(t.abs_diff_eq(Vec3A::splat(filled), 1e-4), "{t}");
+        }
+        assert!((probes[buried].dir_shadow - 0.5).abs() < 1e-5);
+        // Everything else untouched.
+        for (k, p) in probes.iter().enumerate() {
+            if k != buried && k != outlier {
+                assert!(p.texels.iter().all(|t| *t == Vec3A::ONE), "probe {k}");
+                assert_eq!(p.dir_shadow, 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn buried_probes_out_of_reach_are_left() {
+        // Only the first probe of a row is valid, the last one is too far to be filled.
+        let res = uvec3(5, 1, 1);
+        let mut probes: Vec<ProbeTexels> = (0..5).map(|_| probe(7.0, 0.0, 1.0)).collect();
+        probes[0] = probe(1.0, 1.0, 0.0);
+
+        let stats = repair_probes(&mut probes, res, &ProbeRepairSettings::default());
+        assert_eq!(stats.buried, 4);
+        assert_eq!(stats.unfilled, 1);
+        for p in &probes[..4] {
+            assert!(p.texels.iter().all(|t| *t == Vec3A::ONE));
+        }
+        assert!(probes[4].texels.iter().all(|t| *t == Vec3A::splat(7.0)));
+    }
+}
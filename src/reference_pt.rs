@@ -0,0 +1,194 @@
+//! CPU reference path tracer. Renders the active camera's view of the `RtScene` the probe baker
+//! sees to a png in `reference_pt/`, exposed and tonemapped like `std_mat.frag` so it can be
+//! diffed against the realtime output. Press F6, or pass `--reference-pt` with `--bake`.
+
+use std::{path::PathBuf, time::Instant};
+
+use bevy::{camera::Exposure, prelude::*};
+use image::{Rgb, RgbImage};
+use obvhs::ray::Ray;
+use rayon::prelude::*;
+
+use crate::{
+    cascade::SceneBakeName,
+    rt_scene::{RtRng, RtSceneSources},
+};
+
+/// Matches `blender_exposure` in std_mat.frag.
+const BLENDER_EXPOSURE: f32 = 0.2;
+
+#[derive(Resource, Default)]
+pub struct ReferencePtPlugin;
+
+impl Plugin for ReferencePtPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReferencePtSettings>().add_systems(
+            Update,
+            (
+                request_reference_pt,
+                render_reference_pt.run_if(resource_exists::<ReferencePtRequest>),
+            )
+                .chain(),
+        );
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct ReferencePtSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub bounces: u32,
+    pub output_dir: PathBuf,
+}
+
+impl Default for ReferencePtSettings {
+    fn default() -> Self {
+        ReferencePtSettings {
+            width: 960,
+            height: 540,
+            samples_per_pixel: 64,
+            bounces: 3,
+            output_dir: PathBuf::from("reference_pt"),
+        }
+    }
+}
+
+/// Renders the camera view on the next update, then is removed.
+#[derive(Resource, Default)]
+pub struct ReferencePtRequest {
+    /// Prefix of the png's file name. Without it the loaded scene's `SceneBakeName` is used.
+    pub scene_name: Option<String>,
+}
+
+fn request_reference_pt(mut commands: Commands, key: Res<ButtonInput<KeyCode>>) {
+    if key.just_pressed(KeyCode::F6) {
+        commands.insert_resource(ReferencePtRequest::default());
+    }
+}
+
+fn render_reference_pt(
+    mut commands: Commands,
+    request: Res<ReferencePtRequest>,
+    cameras: Query<(&Camera, &GlobalTransform, &Projection, Option<&Exposure>)>,
+    scene_names: Query<&SceneBakeName>,
+    sources: RtSceneSources,
+    settings: Res<ReferencePtSettings>,
+) {
+    commands.remove_resource::<ReferencePtRequest>();
+    let Some((transform, fov, exposure)) = cameras.iter().find_map(
+        |(camera, transform, projection, exposure)| match projection {
+            Projection::Perspective(p) if camera.is_active => Some((
+                transform,
+                p.fov,
+                exposure.map_or(Exposure::default().exposure(), Exposure::exposure),
+            )),
+            _ => None,
+        },
+    ) else {
+        warn!("No active perspective camera to render the reference from");
+        return;
+    };
+
+    let start = Instant::now();
+    let scene = sources.build();
+    let (width, height) = (settings.width, settings.height);
+    let tan_half_fov = (fov * 0.5).tan();
+    let aspect = width as f32 / height as f32;
+    let origin = transform.translation_vec3a();
+    let rotation = transform.rotation();
+
+    let pixels: Vec<Vec3A> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let mut rng = RtRng::new(i as u64 + 1);
+            let mut sum = Vec3A::ZERO;
+            for _ in 0..settings.samples_per_pixel {
+                let uv = (vec2(x as f32, y as f32) + vec2(rng.next_f32(), rng.next_f32()))
+                    / vec2(width as f32, height as f32);
+                let ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+                let view_dir = vec3a(ndc.x * tan_half_fov * aspect, ndc.y * tan_half_fov, -1.0);
+                let dir = (rotation * view_dir).normalize();
+                sum += scene.radiance(Ray::new_inf(origin, dir), settings.bounces, &mut rng);
+            }
+            sum / settings.samples_per_pixel.max(1) as f32
+        })
+        .collect();
+
+    let mut image = RgbImage::new(width, height);
+    for (i, radiance) in pixels.iter().enumerate() {
+        let color = agx_tonemapping(Vec3::from(*radiance) * exposure * BLENDER_EXPOSURE);
+        let color = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round();
+        image.put_pixel(
+            i as u32 % width,
+            i as u32 / width,
+            Rgb([color.x as u8, color.y as u8, color.z as u8]),
+        );
+    }
+
+    // Milliseconds, so renders of several scenes in one bake run don't overwrite each other.
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let scene_name = request
+        .scene_name
+        .clone()
+        .or_else(|| scene_names.iter().next().map(|name| name.0.clone()))
+        .unwrap_or_else(|| String::from("view"))
+        .to_lowercase();
+    let path = settings
+        .output_dir
+        .join(format!("{scene_name}_{timestamp}.png"));
+    let result = std::fs::create_dir_all(&settings.output_dir)
+        .map_err(image::ImageError::IoError)
+        .and_then(|()| image.save(&path));
+    match result {
+        Ok(()) => info!(
+            "Rendered reference to {} in {:.2}s",
+            path.display(),
+            start.elapsed().as_secs_f32()
+        ),
+        Err(e) => error!("Failed to write reference render {}: {e}", path.display()),
+    }
+}
+
+/// Minimal AgX curve fit, approximating `agx_tonemapping` from the std::agx shader include.
+/// Linear in, srgb out.
+fn agx_tonemapping(color: Vec3) -> Vec3 {
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+    let agx_mat = Mat3::from_cols_array(&[
+        0.84247905,
+        0.042328242,
+        0.042375654,
+        0.0784336,
+        0.87846863,
+        0.0784336,
+        0.079223745,
+        0.07916613,
+        0.879143,
+    ]);
+    let agx_mat_inv = Mat3::from_cols_array(&[
+        1.196879,
+        -0.052896854,
+        -0.052971635,
+        -0.09802088,
+        1.1519032,
+        -0.09804345,
+        -0.09902974,
+        -0.098961174,
+        1.1510737,
+    ]);
+    let x = (agx_mat * color.max(Vec3::splat(1e-10)))
+        .to_array()
+        .map(|c| (c.log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV));
+    let x = Vec3::from(x);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let contrast =
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232;
+    agx_mat_inv * contrast
+}
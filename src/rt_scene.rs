@@ -0,0 +1,487 @@
+//! CPU ray tracing scene used for baking. Flattens the scene meshes into a triangle BVH with a
+//! simple diffuse material per triangle.
+
+use std::{f32::consts::PI, time::Duration};
+
+use bevy::{
+    ecs::system::SystemParam, mesh::PrimitiveTopology, platform::collections::HashMap, prelude::*,
+};
+use obvhs::{
+    BvhBuildParams,
+    aabb::Aabb,
+    bvh2::{Bvh2, builder::build_bvh2},
+    ray::Ray,
+};
+use wgpu_types::TextureFormat;
+
+use crate::prepare_lighting::DynamicLight;
+
+/// Radiance of rays that escape the scene.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct RtEnvColor(pub Vec3A);
+
+/// Excludes a mesh or light from baking. For things that move, like the airships.
+#[derive(Component)]
+pub struct NoBake;
+
+/// Offset along the normal for secondary rays.
+const RAY_BIAS: f32 = 0.001;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RtMaterial {
+    pub albedo: Vec3A,
+    pub emissive: Vec3A,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RtLightKind {
+    Point {
+        position: Vec3A,
+        range: f32,
+    },
+    Spot {
+        position: Vec3A,
+        direction: Vec3A,
+        range: f32,
+        cos_inner: f32,
+        cos_outer: f32,
+    },
+    /// `direction` points toward the light.
+    Directional {
+        direction: Vec3A,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RtLight {
+    pub kind: RtLightKind,
+    /// Candela for point and spot lights, lux for directional lights.
+    pub intensity: Vec3A,
+    /// Whether the light is rendered in realtime. Realtime lights only contribute their bounce
+    /// to the bake.
+    pub realtime: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RtHit {
+    pub t: f32,
+    pub triangle: u32,
+    /// Geometric normal, facing the ray origin.
+    pub normal: Vec3A,
+    /// The ray hit the back of the triangle.
+    pub backface: bool,
+}
+
+pub struct RtScene {
+    pub triangles: Vec<[Vec3A; 3]>,
+    pub triangle_materials: Vec<u32>,
+    pub materials: Vec<RtMaterial>,
+    pub lights: Vec<RtLight>,
+    pub env_color: Vec3A,
+    bvh: Option<Bvh2>,
+}
+
+/// Gathers everything that isn't `NoBake` into an `RtScene`.
+#[derive(SystemParam)]
+pub struct RtSceneSources<'w, 's> {
+    meshes: Query<
+        'w,
+        's,
+        (
+            &'static Mesh3d,
+            &'static GlobalTransform,
+            &'static MeshMaterial3d<StandardMaterial>,
+        ),
+        Without<NoBake>,
+    >,
+    point_lights: Query<
+        'w,
+        's,
+        (
+            &'static PointLight,
+            &'static GlobalTransform,
+            Has<DynamicLight>,
+        ),
+        Without<NoBake>,
+    >,
+    spot_lights: Query<
+        'w,
+        's,
+        (
+            &'static SpotLight,
+            &'static GlobalTransform,
+            Has<DynamicLight>,
+        ),
+        Without<NoBake>,
+    >,
+    directional_lights:
+        Query<'w, 's, (&'static DirectionalLight, &'static GlobalTransform), Without<NoBake>>,
+    mesh_assets: Res<'w, Assets<Mesh>>,
+    material_assets: Res<'w, Assets<StandardMaterial>>,
+    images: Res<'w, Assets<Image>>,
+    env_color: Res<'w, RtEnvColor>,
+}
+
+impl RtSceneSources<'_, '_> {
+    pub fn build(&self) -> RtScene {
+        let mut triangles = Vec::new();
+        let mut triangle_materials = Vec::new();
+        let mut materials = Vec::new();
+        let mut material_indices = HashMap::new();
+
+        for (mesh, transform, material_h) in &self.meshes {
+            let Some(mesh) = self.mesh_assets.get(mesh) else {
+                continue;
+            };
+            let Some(material) = self.material_assets.get(material_h) else {
+                continue;
+            };
+            if matches!(material.alpha_mode, AlphaMode::Blend | AlphaMode::Add) {
+                continue;
+            }
+            let material_idx = *material_indices.entry(material_h.id()).or_insert_with(|| {
+                materials.push(rt_material(material, &self.images));
+                materials.len() as u32 - 1
+            });
+            let world_from_local = transform.to_matrix();
+            for tri in mesh_triangles(mesh) {
+                triangles.push(tri.map(|v| world_from_local.transform_point3a(v)));
+                triangle_materials.push(material_idx);
+            }
+        }
+
+        let mut lights = Vec::new();
+        for (light, transform, realtime) in &self.point_lights {
+            lights.push(RtLight {
+                kind: RtLightKind::Point {
+                    position: transform.translation().into(),
+                    range: light.range,
+                },
+                intensity: lumens_to_candela(light.color, light.intensity),
+                realtime,
+            });
+        }
+        for (light, transform, realtime) in &self.spot_lights {
+            lights.push(RtLight {
+                kind: RtLightKind::Spot {
+                    position: transform.translation().into(),
+                    direction: transform.forward().as_vec3().into(),
+                    range: light.range,
+                    cos_inner: light.inner_angle.cos(),
+                    cos_outer: light.outer_angle.cos(),
+                },
+                intensity: lumens_to_candela(light.color, light.intensity),
+                realtime,
+            });
+        }
+        for (light, transform) in &self.directional_lights {
+            lights.push(RtLight {
+                kind: RtLightKind::Directional {
+                    direction: (-transform.forward().as_vec3()).into(),
+                },
+                intensity: light.color.to_linear().to_vec3().to_vec3a() * light.illuminance,
+                realtime: true,
+            });
+        }
+
+        RtScene::new(
+            triangles,
+            triangle_materials,
+            materials,
+            lights,
+            self.env_color.0,
+        )
+    }
+}
+
+fn lumens_to_candela(color: Color, lumens: f32) -> Vec3A {
+    color.to_linear().to_vec3().to_vec3a() * lumens / (4.0 * PI)
+}
+
+fn rt_material(material: &StandardMaterial, images: &Assets<Image>) -> RtMaterial {
+    let mut albedo = material.base_color.to_linear().to_vec3().to_vec3a();
+    if let Some(texture) = &material.base_color_texture
+        && let Some(image) = images.get(texture)
+        && let Some(average) = average_color(image)
+    {
+        albedo *= average;
+    }
+    RtMaterial {
+        // Keep a little energy out so bounces converge.
+        albedo: albedo.min(Vec3A::splat(0.95)),
+        emissive: material.emissive.to_vec3().to_vec3a(),
+    }
+}
+
+/// Average linear color of an uncompressed rgba8 image, None for other formats.
+fn average_color(image: &Image) -> Option<Vec3A> {
+    let srgb = match image.texture_descriptor.format {
+        TextureFormat::Rgba8UnormSrgb => true,
+        TextureFormat::Rgba8Unorm => false,
+        _ => return None,
+    };
+    let data = image.data.as_ref()?;
+    let pixels = data.len() / 4;
+    if pixels == 0 {
+        return None;
+    }
+    // Don't need every pixel of big textures for an average.
+    let stride = (pixels / 4096).max(1);
+    let mut sum = Vec3A::ZERO;
+    let mut count = 0;
+    for p in data.chunks_exact(4).step_by(stride) {
+        let c = if srgb {
+            Color::srgb_u8(p[0], p[1], p[2])
+                .to_linear()
+                .to_vec3()
+                .to_vec3a()
+        } else {
+            vec3a(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0
+        };
+        sum += c;
+        count += 1;
+    }
+    Some(sum / count as f32)
+}
+
+fn mesh_triangles(mesh: &Mesh) -> Vec<[Vec3A; 3]> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        return Vec::new();
+    }
+    let Some(positions) = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|a| a.as_float3())
+    else {
+        return Vec::new();
+    };
+    let vertex = |i: usize| Vec3A::from(positions[i]);
+    match mesh.indices() {
+        Some(indices) => indices
+            .iter()
+            .collect::<Vec<_>>()
+            .chunks_exact(3)
+            .map(|t| [vertex(t[0]), vertex(t[1]), vertex(t[2])])
+            .collect(),
+        None => (0..positions.len() / 3)
+            .map(|i| [vertex(i * 3), vertex(i * 3 + 1), vertex(i * 3 + 2)])
+            .collect(),
+    }
+}
+
+impl RtScene {
+    pub fn new(
+        triangles: Vec<[Vec3A; 3]>,
+        triangle_materials: Vec<u32>,
+        materials: Vec<RtMaterial>,
+        lights: Vec<RtLight>,
+        env_color: Vec3A,
+    ) -> Self {
+        let aabbs = triangles
+            .iter()
+            .map(|tri| {
+                let mut aabb = Aabb::empty();
+                for v in tri {
+                    aabb.extend(*v);
+                }
+                aabb
+            })
+            .collect::<Vec<_>>();
+        let bvh = (!aabbs.is_empty()).then(|| {
+            build_bvh2(
+                &aabbs,
+                BvhBuildParams::fast_build(),
+                &mut Duration::default(),
+            )
+        });
+        RtScene {
+            triangles,
+            triangle_materials,
+            materials,
+            lights,
+            env_color,
+            bvh,
+        }
+    }
+
+    pub fn material(&self, hit: &RtHit) -> &RtMaterial {
+        &self.materials[self.triangle_materials[hit.triangle as usize] as usize]
+    }
+
+    /// Closest hit closer than `max_t`.
+    pub fn intersect(&self, ray: &Ray, max_t: f32) -> Option<RtHit> {
+        let bvh = self.bvh.as_ref()?;
+        let mut closest: Option<(f32, u32)> = None;
+        let mut closest_t = max_t;
+        let mut stack = vec![0u32];
+        while let Some(node_index) = stack.pop() {
+            let node = &bvh.nodes[node_index as usize];
+            if node.aabb.intersect_ray(ray) >= closest_t {
+                continue;
+            }
+            if node.is_leaf() {
+                let start = node.first_index as usize;
+                let end = start + node.prim_count as usize;
+                for &tri in &bvh.primitive_indices[start..end] {
+                    let t = intersect_triangle(ray, &self.triangles[tri as usize]);
+                    if t < closest_t {
+                        closest_t = t;
+                        closest = Some((t, tri));
+                    }
+                }
+            } else {
+                stack.push(node.first_index);
+                stack.push(node.first_index + 1);
+            }
+        }
+        let (t, triangle) = closest?;
+        let [v0, v1, v2] = self.triangles[triangle as usize];
+        let normal = (v1 - v0).cross(v2 - v0).normalize_or_zero();
+        let backface = normal.dot(ray.direction) > 0.0;
+        Some(RtHit {
+            t,
+            triangle,
+            normal: if backface { -normal } else { normal },
+            backface,
+        })
+    }
+
+    pub fn occluded(&self, origin: Vec3A, direction: Vec3A, max_t: f32) -> bool {
+        self.intersect(&Ray::new_inf(origin, direction), max_t)
+            .is_some()
+    }
+
+    /// Direction toward the light and the illuminance it gives a surface facing it at `position`,
+    /// None if the light doesn't reach.
+    pub fn light_illuminance(&self, light: &RtLight, position: Vec3A) -> Option<(Vec3A, Vec3A)> {
+        let (direction, distance, attenuation) = match light.kind {
+            RtLightKind::Point {
+                position: light_pos,
+                range,
+            } => {
+                let to_light = light_pos - position;
+                let distance = to_light.length();
+                if distance > range || distance <= 0.0 {
+                    return None;
+                }
+                (to_light / distance, distance, 1.0 / (distance * distance))
+            }
+            RtLightKind::Spot {
+                position: light_pos,
+                direction: spot_dir,
+                range,
+                cos_inner,
+                cos_outer,
+            } => {
+                let to_light = light_pos - position;
+                let distance = to_light.length();
+                if distance > range || distance <= 0.0 {
+                    return None;
+                }
+                let dir = to_light / distance;
+                let cos_angle = (-dir).dot(spot_dir);
+                let t =
+                    ((cos_angle - cos_outer) / (cos_inner - cos_outer).max(1e-4)).clamp(0.0, 1.0);
+                let spot = t * t * (3.0 - 2.0 * t);
+                if spot <= 0.0 {
+                    return None;
+                }
+                (dir, distance, spot / (distance * distance))
+            }
+            RtLightKind::Directional { direction } => (direction, f32::INFINITY, 1.0),
+        };
+        if self.occluded(position, direction, distance) {
+            return None;
+        }
+        Some((direction, light.intensity * attenuation))
+    }
+
+    /// Illuminance at a surface from all lights.
+    pub fn direct_illuminance(&self, position: Vec3A, normal: Vec3A) -> Vec3A {
+        self.lights
+            .iter()
+            .filter_map(|light| self.light_illuminance(light, position))
+            .map(|(dir, e)| e * normal.dot(dir).max(0.0))
+            .sum()
+    }
+
+    /// Path traced radiance arriving at the ray origin from the ray direction.
+    pub fn radiance(&self, mut ray: Ray, bounces: u32, rng: &mut RtRng) -> Vec3A {
+        let mut throughput = Vec3A::ONE;
+        let mut radiance = Vec3A::ZERO;
+        for bounce in 0..=bounces {
+            let Some(hit) = self.intersect(&ray, f32::INFINITY) else {
+                radiance += throughput * self.env_color;
+                break;
+            };
+            let material = self.material(&hit);
+            let position = ray.origin + ray.direction * hit.t + hit.normal * RAY_BIAS;
+            radiance += throughput * material.emissive;
+            radiance +=
+                throughput * material.albedo / PI * self.direct_illuminance(position, hit.normal);
+            if bounce == bounces {
+                break;
+            }
+            // Cosine weighted sampling cancels the lambert cos/pi, leaving just the albedo.
+            throughput *= material.albedo;
+            ray = Ray::new_inf(position, cosine_hemisphere(hit.normal, rng));
+        }
+        radiance
+    }
+}
+
+/// Möller-Trumbore, returns infinity on miss.
+fn intersect_triangle(ray: &Ray, tri: &[Vec3A; 3]) -> f32 {
+    let e1 = tri[1] - tri[0];
+    let e2 = tri[2] - tri[0];
+    let p = ray.direction.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < 1e-9 {
+        return f32::INFINITY;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - tri[0];
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return f32::INFINITY;
+    }
+    let q = s.cross(e1);
+    let v = ray.direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return f32::INFINITY;
+    }
+    let t = e2.dot(q) * inv_det;
+    if t > 0.0 { t } else { f32::INFINITY }
+}
+
+/// Small deterministic rng so bakes are reproducible.
+pub struct RtRng(u64);
+
+impl RtRng {
+    pub fn new(seed: u64) -> Self {
+        RtRng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (x >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+pub fn uniform_sphere(rng: &mut RtRng) -> Vec3A {
+    let z = 1.0 - 2.0 * rng.next_f32();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f32();
+    vec3a(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn cosine_hemisphere(normal: Vec3A, rng: &mut RtRng) -> Vec3A {
+    let r = rng.next_f32().sqrt();
+    let phi = 2.0 * PI * rng.next_f32();
+    let local = vec3a(r * phi.cos(), r * phi.sin(), (1.0 - r * r).max(0.0).sqrt());
+    let (t, b) = Vec3::from(normal).any_orthonormal_pair();
+    (Vec3A::from(t) * local.x + Vec3A::from(b) * local.y + normal * local.z).normalize_or(normal)
+}
@@ -6,7 +6,7 @@ use bevy_seedling::prelude::*;
 use crate::{
     SceneContents, SceneState,
     assets::{AudioAssets, SceneAssets},
-    cascade::{self, SceneBakeName},
+    cascade::{self, CascadeFallback, SceneBakeName},
     despawn_scene_contents,
     physics::tri_mesh_collider,
     post_process::PostProcessSettings,
@@ -35,10 +35,9 @@ pub struct FallingScene;
 pub fn load_falling(
     mut commands: Commands,
     mut fog: ResMut<Fog>,
+    mut cascade_fallback: ResMut<CascadeFallback>,
     mut sun: Single<&mut DirectionalLight>,
-    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<
-        light_volume_baker::rt_scene::RtEnvColor,
-    >,
+    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<crate::rt_scene::RtEnvColor>,
     player: Single<(&mut Transform, &mut LinearVelocity, &mut FpsController), With<LogicalPlayer>>,
     mut post_process: ResMut<PostProcessSettings>,
     mut next_state: ResMut<NextState<SceneState>>,
@@ -51,7 +50,9 @@ pub fn load_falling(
         rt_env_color.0 = vec3a(1.0, 1.5, 1.8);
     }
     next_state.set(SceneState::Falling);
-    post_process.enable = false;
+    // The sun and the caustics go far past 1.0. At 0 stops the tonemap matches what std_mat's own
+    // AgX gave, without the clipping.
+    *post_process = PostProcessSettings::default().with_hdr(0.0);
     *state = Default::default();
 
     commands.spawn((
@@ -75,8 +76,15 @@ pub fn load_falling(
     sun.illuminance = 100000.0;
     sun.shadows_enabled = false;
 
-    fog.fog_color = vec4(0.01, 0.015, 0.025, 0.002);
-    fog.caustics = vec4(70.0, 70.0, 70.0, 1.0);
+    *fog = Fog {
+        fog_color: vec4(0.01, 0.015, 0.025, 0.002),
+        caustics: vec4(70.0, 70.0, 70.0, 1.0),
+        ..default()
+    };
+    *cascade_fallback = CascadeFallback {
+        ambient: vec3(200.0, 300.0, 360.0),
+        ..default()
+    };
 
     commands
         .spawn((
@@ -6,11 +6,13 @@ use bevy_seedling::prelude::*;
 use crate::{
     SceneContents, SceneState,
     assets::{AudioAssets, SceneAssets},
-    cascade::{self, SceneBakeName},
+    cascade::{self, CascadeFallback, SceneBakeName},
     despawn_scene_contents,
     draw_debug::DebugLines,
+    fog_volume::{FogVolume, FogVolumeShape},
     physics::{convex_hull_dyn_collider_indv, tri_mesh_collider},
-    post_process::PostProcessSettings,
+    post_process::{PostEffectKind, PostProcessSettings},
+    prepare_lighting::{LightExtras, SceneLightDefaults, apply_light_extras},
     scene_falling::load_falling,
     scene_store::{HeldBox, MacBox, ThrownBox},
     std_mat_render::Fog,
@@ -40,10 +42,9 @@ pub struct HallwayScene;
 pub fn load_hallway(
     mut commands: Commands,
     mut fog: ResMut<Fog>,
+    mut cascade_fallback: ResMut<CascadeFallback>,
     mut sun: Single<&mut DirectionalLight>,
-    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<
-        light_volume_baker::rt_scene::RtEnvColor,
-    >,
+    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<crate::rt_scene::RtEnvColor>,
     player: Single<(&mut Transform, &mut LinearVelocity, &mut FpsController), With<LogicalPlayer>>,
     mut post_process: ResMut<PostProcessSettings>,
     mut next_state: ResMut<NextState<SceneState>>,
@@ -56,7 +57,19 @@ pub fn load_hallway(
         rt_env_color.0 = Vec3A::ZERO;
     }
     next_state.set(SceneState::Hallway);
-    post_process.enable = true;
+    // One draw, the pixel effects ride along with the aberration.
+    *post_process = PostProcessSettings::new([
+        PostEffectKind::ChromaticAberration { offset: 0.005 },
+        PostEffectKind::Vignette {
+            strength: 0.5,
+            radius: 0.45,
+        },
+        PostEffectKind::FilmGrain { strength: 0.025 },
+        PostEffectKind::ColorTint {
+            color: Color::srgb(0.85, 1.0, 0.85),
+            strength: 0.3,
+        },
+    ]);
     *state = Default::default();
 
     commands.spawn((
@@ -80,8 +93,15 @@ pub fn load_hallway(
     sun.illuminance = 0.0;
     sun.shadows_enabled = false;
 
-    fog.fog_color = vec4(5.0, 5.0, 5.0, 0.02);
-    fog.caustics = vec4(0.6, 0.0, 0.0, 0.0);
+    *fog = Fog {
+        fog_color: vec4(5.0, 5.0, 5.0, 0.02),
+        caustics: vec4(0.6, 0.0, 0.0, 0.0),
+        ..default()
+    };
+    *cascade_fallback = CascadeFallback {
+        ambient: vec3(100.0, 100.0, 100.0),
+        ..default()
+    };
 
     commands
         .spawn((
@@ -89,22 +109,13 @@ pub fn load_hallway(
             HallwayScene,
             SceneContents,
             SceneBakeName(String::from("Hallway")),
+            SceneLightDefaults(LightExtras {
+                shadows: Some(true),
+                ..default()
+            }),
         ))
         .observe(cascade::blender_cascades)
-        .observe(
-            |scene_ready: On<SceneInstanceReady>,
-             children: Query<&Children>,
-             mut point_lights: Query<&mut PointLight>,
-             mut spot_lights: Query<&mut SpotLight>| {
-                for entity in children.iter_descendants(scene_ready.entity) {
-                    if let Ok(mut point_light) = point_lights.get_mut(entity) {
-                        point_light.shadows_enabled = true;
-                    } else if let Ok(mut spot_light) = spot_lights.get_mut(entity) {
-                        spot_light.shadows_enabled = true;
-                    }
-                }
-            },
-        );
+        .observe(apply_light_extras);
 
     commands
         .spawn((
@@ -157,6 +168,22 @@ pub fn load_hallway(
         Transform::from_xyz(0.0, 1.666, -19.05),
         Ghost,
     ));
+
+    // Murky air pooling on the floor in the corner by the ghost.
+    commands.spawn((
+        FogVolume {
+            shape: FogVolumeShape::Box {
+                half_size: vec3(1.2, 0.7, 2.0),
+            },
+            density: 0.5,
+            color: vec3(0.45, 0.5, 0.42),
+            falloff: 0.5,
+            caustics: false,
+        },
+        Transform::from_xyz(-1.0, 0.7, -17.0),
+        HallwayScene,
+        SceneContents,
+    ));
 }
 
 #[derive(Component)]
@@ -32,7 +32,7 @@ impl Plugin for StoreSceneGameplayPlugin {
 use crate::{
     SceneContents, SceneState,
     assets::{AudioAssets, SceneAssets},
-    cascade::{self, SceneBakeName},
+    cascade::{self, CascadeFallback, SceneBakeName},
     despawn_scene_contents,
     draw_debug::DebugLines,
     physics::{
@@ -40,6 +40,7 @@ use crate::{
         trimesh_dyn_collider_scene,
     },
     post_process::PostProcessSettings,
+    prepare_lighting::{LightExtras, SceneLightDefaults, apply_light_extras},
     scene_underwater::load_underwater,
     std_mat_render::Fog,
 };
@@ -47,10 +48,9 @@ use crate::{
 pub fn load_store(
     mut commands: Commands,
     mut fog: ResMut<Fog>,
+    mut cascade_fallback: ResMut<CascadeFallback>,
     mut sun: Single<&mut DirectionalLight>,
-    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<
-        light_volume_baker::rt_scene::RtEnvColor,
-    >,
+    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<crate::rt_scene::RtEnvColor>,
     player: Single<(&mut Transform, &mut LinearVelocity, &mut FpsController), With<LogicalPlayer>>,
     mut post_process: ResMut<PostProcessSettings>,
     mut state: ResMut<PlayerStoreState>,
@@ -63,7 +63,7 @@ pub fn load_store(
         rt_env_color.0 = Vec3A::ZERO;
     }
     next_state.set(SceneState::Store);
-    post_process.enable = false;
+    *post_process = PostProcessSettings::default();
     *state = Default::default();
 
     commands.spawn((
@@ -88,8 +88,11 @@ pub fn load_store(
     sun.shadows_enabled = false;
 
     //fog.fog_color = vec4(0.01, 0.01, 0.01, 1.0);
-    fog.fog_color = Vec4::ZERO;
-    fog.caustics = Vec4::ZERO;
+    *fog = Fog::default();
+    *cascade_fallback = CascadeFallback {
+        ambient: vec3(500.0, 500.0, 500.0),
+        ..default()
+    };
 
     let shelf = &assets.store_shelf;
 
@@ -155,22 +158,13 @@ pub fn load_store(
             StoreScene,
             SceneContents,
             SceneBakeName(String::from("Store")),
+            SceneLightDefaults(LightExtras {
+                shadows: Some(true),
+                ..default()
+            }),
         ))
         .observe(cascade::blender_cascades)
-        .observe(
-            |scene_ready: On<SceneInstanceReady>,
-             children: Query<&Children>,
-             mut point_lights: Query<&mut PointLight>,
-             mut spot_lights: Query<&mut SpotLight>| {
-                for entity in children.iter_descendants(scene_ready.entity) {
-                    if let Ok(mut point_light) = point_lights.get_mut(entity) {
-                        point_light.shadows_enabled = true;
-                    } else if let Ok(mut spot_light) = spot_lights.get_mut(entity) {
-                        spot_light.shadows_enabled = true;
-                    }
-                }
-            },
-        )
+        .observe(apply_light_extras)
         .observe(tri_mesh_collider);
 }
 
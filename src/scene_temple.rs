@@ -1,9 +1,14 @@
-use bevy::{light::light_consts::lux::DIRECT_SUNLIGHT, prelude::*, scene::SceneInstanceReady};
+use bevy::{light::light_consts::lux::DIRECT_SUNLIGHT, prelude::*};
 use bgl2::phase_shadow::ShadowBounds;
 
 use crate::{
-    SceneContents, cascade::CascadeInput, post_process::PostProcessSettings,
-    prepare_lighting::DynamicLight, std_mat_render::Fog,
+    SceneContents,
+    cascade::CascadeFallback,
+    cascade_manifest::SceneCascadeManifest,
+    post_process::PostProcessSettings,
+    prepare_lighting::{LightExtras, SceneLightDefaults, apply_light_extras},
+    std_mat_render::Fog,
+    time_of_day::{SunCurve, SunKeyframe, TimeOfDay},
 };
 
 #[derive(Component)]
@@ -13,10 +18,9 @@ pub fn load_temple(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut fog: ResMut<Fog>,
+    mut cascade_fallback: ResMut<CascadeFallback>,
     sun: Single<(&mut DirectionalLight, &mut ShadowBounds)>,
-    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<
-        light_volume_baker::rt_scene::RtEnvColor,
-    >,
+    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<crate::rt_scene::RtEnvColor>,
     camera: Single<&mut Transform, With<Camera3d>>,
     mut settings: ResMut<PostProcessSettings>,
 ) {
@@ -25,37 +29,32 @@ pub fn load_temple(
         rt_env_color.0 = vec3a(0.32, 0.4, 0.47) * 2.0;
     }
 
-    settings.enable = false;
+    *settings = PostProcessSettings::default();
 
     *camera.into_inner() =
         Transform::from_xyz(-45.0, 4.0, 0.0).looking_at(Vec3::new(0.0, 18.0, 0.0), Vec3::Y);
 
+    // What gets baked, TimeOfDay takes over from here and stays close to it.
     let (mut sun, mut shadow_bounds) = sun.into_inner();
     sun.illuminance = DIRECT_SUNLIGHT;
     sun.shadows_enabled = true;
     *shadow_bounds = ShadowBounds::cube(250.0);
 
-    fog.fog_color = vec4(1.0, 1.0, 1.0, 1.0);
-    let start = vec3a(-47.5, 0.1, -25.5);
-    let end = vec3a(36.0, 56.0, 34.0) * 2.0 + start;
+    *fog = Fog {
+        fog_color: vec4(1.0, 1.0, 1.0, 1.0),
+        ..default()
+    };
     commands.spawn((
-        CascadeInput {
-            name: String::from("nave"),
-            ws_aabb: obvhs::aabb::Aabb::new(start, end),
-            resolution: vec3a(1.5, 1.5, 1.5),
-        },
+        TimeOfDay::new(10.0, 0.04, temple_sun_curve()),
         SceneContents,
         TempleScene,
     ));
-
-    let start = vec3a(10.5, 0.1, -25.5);
-    let end = vec3a(26.0, 86.0, 34.0) * 2.0 + start;
+    *cascade_fallback = CascadeFallback {
+        ambient: vec3(640.0, 800.0, 940.0),
+        ..default()
+    };
     commands.spawn((
-        CascadeInput {
-            name: String::from("tower"),
-            ws_aabb: obvhs::aabb::Aabb::new(start, end),
-            resolution: vec3a(2.0, 2.0, 2.0),
-        },
+        SceneCascadeManifest(asset_server.load("bake/temple.cascades.json")),
         SceneContents,
         TempleScene,
     ));
@@ -77,28 +76,48 @@ pub fn load_temple(
             ),
             SceneContents,
             TempleScene,
+            SceneLightDefaults(LightExtras {
+                intensity_scale: Some(50.0),
+                dynamic: Some(true),
+                shadows: Some(true),
+                ..default()
+            }),
         ))
-        .observe(
-            |scene_ready: On<SceneInstanceReady>,
-             children: Query<&Children>,
-             mut point_lights: Query<&mut PointLight>,
-             mut spot_lights: Query<&mut SpotLight>,
-             mut commands: Commands| {
-                for entity in children.iter_descendants(scene_ready.entity) {
-                    if let Ok(mut point_light) = point_lights.get_mut(entity) {
-                        point_light.shadows_enabled = true;
-                        point_light.intensity *= 50.0;
-                    } else if let Ok(mut spot_light) = spot_lights.get_mut(entity) {
-                        spot_light.shadows_enabled = true;
-                        spot_light.intensity *= 50.0;
-                    } else {
-                        continue;
-                    };
-                    let mut ecmds = commands.entity(entity);
-                    ecmds.insert(DynamicLight);
-                    #[cfg(feature = "asset_baking")]
-                    ecmds.insert(light_volume_baker::rt_scene::NoBake);
-                }
-            },
-        );
+        .observe(apply_light_extras);
+}
+
+/// Stays in daylight around the sun `setup` spawns, which is what the probes and the fallback
+/// ambient are baked with, so bounce light stays plausible. After the afternoon key the sun eases
+/// back to the morning one.
+fn temple_sun_curve() -> SunCurve {
+    let noon = SunKeyframe {
+        hour: 12.0,
+        // The baked direction.
+        elevation: 60.8f32.to_radians(),
+        azimuth: -45.7f32.to_radians(),
+        color: Color::srgb(1.0, 0.9, 0.8),
+        illuminance: DIRECT_SUNLIGHT,
+        shadow_extent: 250.0,
+        clear_color: Color::srgb(0.32, 0.4, 0.47),
+        fog_color: vec4(1.0, 1.0, 1.0, 1.0),
+    };
+    let morning = SunKeyframe {
+        hour: 8.0,
+        elevation: 40f32.to_radians(),
+        azimuth: -85f32.to_radians(),
+        color: Color::srgb(1.0, 0.82, 0.68),
+        illuminance: DIRECT_SUNLIGHT * 0.75,
+        shadow_extent: 300.0,
+        clear_color: Color::srgb(0.4, 0.42, 0.46),
+        fog_color: vec4(1.0, 0.9, 0.8, 1.0),
+    };
+    SunCurve(vec![
+        morning,
+        noon,
+        SunKeyframe {
+            hour: 16.0,
+            azimuth: -6f32.to_radians(),
+            ..morning
+        },
+    ])
 }
@@ -1,18 +1,18 @@
 use core::f32;
 
 use avian3d::prelude::*;
-use bevy::{prelude::*, scene::SceneInstanceReady};
+use bevy::{light::NotShadowCaster, prelude::*, scene::SceneInstanceReady};
 use bevy_fps_controller::controller::{FpsController, LogicalPlayer};
 use bevy_seedling::prelude::*;
 
 use crate::{
     SceneContents, SceneState,
     assets::{AudioAssets, SceneAssets},
-    cascade::{self, SceneBakeName},
+    cascade::{self, CascadeFallback, SceneBakeName},
     despawn_scene_contents,
     physics::tri_mesh_collider,
     post_process::PostProcessSettings,
-    prepare_lighting::DynamicLight,
+    prepare_lighting::{DynamicLight, LightAnimation, LightCurve, apply_light_extras},
     scene_hallway::load_hallway,
     std_mat_render::Fog,
 };
@@ -43,10 +43,9 @@ pub struct UnderwaterBeamSfx;
 pub fn load_underwater(
     mut commands: Commands,
     mut fog: ResMut<Fog>,
+    mut cascade_fallback: ResMut<CascadeFallback>,
     mut sun: Single<&mut DirectionalLight>,
-    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<
-        light_volume_baker::rt_scene::RtEnvColor,
-    >,
+    #[cfg(feature = "asset_baking")] mut rt_env_color: ResMut<crate::rt_scene::RtEnvColor>,
     player: Single<(&mut Transform, &mut LinearVelocity, &mut FpsController), With<LogicalPlayer>>,
     mut post_process: ResMut<PostProcessSettings>,
     mut next_state: ResMut<NextState<SceneState>>,
@@ -60,7 +59,7 @@ pub fn load_underwater(
         rt_env_color.0 = vec3a(1.0, 1.5, 1.8) * 0.05;
     }
     next_state.set(SceneState::Underwater);
-    post_process.enable = false;
+    *post_process = PostProcessSettings::default();
     *state = Default::default();
 
     commands.spawn((
@@ -99,8 +98,19 @@ pub fn load_underwater(
     sun.illuminance = 0.0;
     sun.shadows_enabled = false;
 
-    fog.fog_color = vec4(0.1, 0.2, 0.5, 0.02);
-    fog.caustics = vec4(0.3, 0.6, 1.0, 1.0);
+    *fog = Fog {
+        fog_color: vec4(0.1, 0.2, 0.5, 0.02),
+        caustics: vec4(0.3, 0.6, 1.0, 1.0),
+        caustics_scroll: vec2(0.15, 0.05),
+        caustics_speed: 0.3,
+        // Murk carried along by the current.
+        fog_noise: vec4(0.4, 0.05, 0.15, 0.5),
+        ..default()
+    };
+    *cascade_fallback = CascadeFallback {
+        ambient: vec3(50.0, 80.0, 150.0),
+        ..default()
+    };
 
     commands
         .spawn((
@@ -128,14 +138,34 @@ pub fn load_underwater(
          mesh_entities: Query<Entity, With<Mesh3d>>| {
             for entity in children.iter_descendants(scene_ready.entity) {
                 if let Ok(entity) = mesh_entities.get(entity) {
-                    commands
-                        .entity(entity)
-                        .insert(light_volume_baker::rt_scene::NoBake);
+                    commands.entity(entity).insert(crate::rt_scene::NoBake);
                 }
             }
         },
     );
 
+    // Beacon over the exit, pulsing so it reads through the murk.
+    #[allow(unused)]
+    let mut ecmds = commands.spawn((
+        PointLight {
+            color: Color::srgb(0.3, 1.0, 0.8),
+            intensity: 200_000.0,
+            range: 25.0,
+            shadows_enabled: false,
+            ..default()
+        },
+        Transform::from_xyz(0.0, 4.0, -74.0),
+        DynamicLight,
+        LightAnimation::new(LightCurve::Pulse {
+            min: 0.2,
+            period: 2.5,
+        }),
+        UnderwaterScene,
+        SceneContents,
+    ));
+    #[cfg(feature = "asset_baking")]
+    ecmds.insert(crate::rt_scene::NoBake);
+
     let ship_scene = &assets.underwater_airship;
     for i in 0..3 {
         let pos = SHIP_DESTINATIONS[i];
@@ -151,7 +181,8 @@ pub fn load_underwater(
                     index: i as u32,
                 },
             ))
-            .observe(proc_ship);
+            .observe(proc_ship)
+            .observe(apply_light_extras);
     }
 
     commands
@@ -273,22 +304,17 @@ const SWEEP_REGIONS: [f32; 3] = [-10.0, -40.0, -70.0];
 fn proc_ship(
     scene_ready: On<SceneInstanceReady>,
     children: Query<&Children>,
-    mut spot_lights: Query<&mut SpotLight>,
     mut commands: Commands,
     named: Query<(Entity, &Name)>,
     airships: Query<&Airship>,
+    meshes: Query<(), With<Mesh3d>>,
 ) {
     if let Ok(airship) = airships.get(scene_ready.entity) {
         for entity in children.iter_descendants(scene_ready.entity) {
-            if let Ok(mut spot_light) = spot_lights.get_mut(entity) {
-                spot_light.shadows_enabled = true;
-                spot_light.intensity *= 1000.0;
-                spot_light.range = 10000.0;
-                let mut ecmds = commands.entity(entity);
-                ecmds.insert(DynamicLight);
-                #[cfg(feature = "asset_baking")]
-                ecmds.insert(light_volume_baker::rt_scene::NoBake);
-            };
+            // The searchlights sit inside the hull, it would block their whole shadow map.
+            if meshes.contains(entity) {
+                commands.entity(entity).insert(NotShadowCaster);
+            }
             if let Ok((entity, name)) = named.get(entity)
                 && name.contains("SEARCH_LIGHT")
             {
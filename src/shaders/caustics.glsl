@@ -80,7 +80,7 @@ float water_caustics(vec3 pos) {
     vec4 n = snoise(pos);
 
     pos -= 0.07 * n.xyz;
-    pos *= 1.62;
+    pos *= 1.625; // 13 / 8, so the octaves repeat together, see `CAUSTICS_PERIOD`
     n = snoise(pos);
 
     pos -= 0.07 * n.xyz;
@@ -0,0 +1,34 @@
+// Fog density noise, shared by std_mat.frag and light_shafts.frag so the shafts thin out where
+// the fog does.
+
+float fog_noise_hash(float n) {
+    return fract(sin(n) * 1e4);
+}
+
+// The lattice wraps at 256 so the noise repeats, see `FOG_NOISE_PERIOD`.
+float fog_noise_lattice(vec3 p) {
+    p = mod(p, 256.0);
+    return fog_noise_hash(p.x + p.y * 57.0 + p.z * 113.0);
+}
+
+float value_noise(vec3 x) {
+    vec3 p = floor(x);
+    vec3 f = fract(x);
+    f = f * f * (3.0 - 2.0 * f);
+    return mix(mix(mix(fog_noise_lattice(p), fog_noise_lattice(p + vec3(1.0, 0.0, 0.0)), f.x),
+            mix(fog_noise_lattice(p + vec3(0.0, 1.0, 0.0)), fog_noise_lattice(p + vec3(1.0, 1.0, 0.0)), f.x), f.y),
+        mix(mix(fog_noise_lattice(p + vec3(0.0, 0.0, 1.0)), fog_noise_lattice(p + vec3(1.0, 0.0, 1.0)), f.x),
+            mix(fog_noise_lattice(p + vec3(0.0, 1.0, 1.0)), fog_noise_lattice(p + vec3(1.0, 1.0, 1.0)), f.x), f.y), f.z);
+}
+
+// Scales fog density at p, 1.0 without noise. noise: xyz drift in meters per second, w strength.
+// offset: how far it has drifted, wrapped by `advance_fog_time`.
+float fog_density_noise_at(vec3 p, vec4 noise, vec3 offset) {
+    if (noise.w <= 0.0) {
+        return 1.0;
+    }
+    vec3 q = (p - offset) * 0.2;
+    // Octaves at whole multiples so they repeat together.
+    float n = value_noise(q) * 0.67 + value_noise(q * 2.0) * 0.33;
+    return mix(1.0, n * 2.0, noise.w);
+}
@@ -0,0 +1,13 @@
+// Fits linear HDR color into an 8 bit target when there is no float one, see `HdrTarget`.
+// Scaled by the brightest channel so hue survives, then gamma encoded so dark values get
+// most of the 8 bits instead of banding.
+
+vec3 hdr_encode(vec3 color) {
+    color /= 1.0 + max(max(color.r, color.g), color.b);
+    return pow(color, vec3(1.0 / 2.2));
+}
+
+vec3 hdr_decode(vec3 color) {
+    color = pow(color, vec3(2.2));
+    return color / max(1.0 - max(max(color.r, color.g), color.b), 1.0 / 1024.0);
+}
@@ -0,0 +1,56 @@
+// Point and spot light shadows, see light_shadows.rs.
+// Tiles store the distance to the light over its range, packed into rgba8.
+
+vec4 pack_light_depth(float v) {
+    vec4 enc = fract(vec4(1.0, 255.0, 65025.0, 16581375.0) * min(v, 0.99999));
+    return enc - enc.yzww * vec4(1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0, 0.0);
+}
+
+float unpack_light_depth(vec4 rgba) {
+    return dot(rgba, vec4(1.0, 1.0 / 255.0, 1.0 / 65025.0, 1.0 / 16581375.0));
+}
+
+// Must match light_shadow_up() on the cpu.
+vec3 light_shadow_up(vec3 forward) {
+    return abs(forward.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(0.0, 0.0, 1.0);
+}
+
+// shadow: x first tile, y tan of the spot's half angle, or 0.0 for point lights which use 6 tiles.
+float sample_light_shadow(sampler2D atlas, float tiles_per_row, vec4 shadow, float range, vec3 spot_dir,
+        vec3 light_to_frag) {
+    float tile = shadow.x;
+    float tan_half_fov = shadow.y;
+    vec3 forward = spot_dir;
+    if (tan_half_fov == 0.0) {
+        // Cube face order +x -x +y -y +z -z
+        vec3 a = abs(light_to_frag);
+        if (a.x >= a.y && a.x >= a.z) {
+            forward = vec3(sign(light_to_frag.x), 0.0, 0.0);
+            tile += light_to_frag.x > 0.0 ? 0.0 : 1.0;
+        } else if (a.y >= a.z) {
+            forward = vec3(0.0, sign(light_to_frag.y), 0.0);
+            tile += light_to_frag.y > 0.0 ? 2.0 : 3.0;
+        } else {
+            forward = vec3(0.0, 0.0, sign(light_to_frag.z));
+            tile += light_to_frag.z > 0.0 ? 4.0 : 5.0;
+        }
+        tan_half_fov = 1.0;
+    }
+    float z = dot(light_to_frag, forward);
+    if (z <= 0.0) {
+        return 1.0;
+    }
+    vec3 right = normalize(cross(forward, light_shadow_up(forward)));
+    vec3 up = cross(right, forward);
+    vec2 ndc = vec2(dot(light_to_frag, right), dot(light_to_frag, up)) / (z * tan_half_fov);
+    if (abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0) {
+        return 1.0;
+    }
+    vec2 tile_xy = vec2(mod(tile, tiles_per_row), floor(tile / tiles_per_row));
+    vec2 uv = (tile_xy + ndc * 0.5 + 0.5) / tiles_per_row;
+    float occluder = unpack_light_depth(texture2D(atlas, uv));
+    float distance = length(light_to_frag);
+    // Texels get larger with distance, so does the bias.
+    float bias = (0.05 + distance * 0.02) / range;
+    return distance / range - bias > occluder ? 0.0 : 1.0;
+}
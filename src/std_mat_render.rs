@@ -1,4 +1,4 @@
-use bevy::{camera::primitives::Aabb, prelude::*};
+use bevy::{camera::primitives::Aabb, math::DVec3, platform::collections::HashMap, prelude::*};
 use bgl2::bevy_standard_lighting::DEFAULT_MAX_LIGHTS_DEF;
 use bgl2::{
     UniformSet, UniformValue,
@@ -20,16 +20,79 @@ use bgl2::{
 use itertools::Either;
 use uniform_set_derive::UniformSet;
 
-use crate::cascade::{CascadeUniform, CascadeViewUniform, select_cascade, transform_aabb};
+use crate::cascade::{
+    CascadeBlendUniform, CascadeBvh, CascadeUniform, CascadeViewUniform, SelectedCascade,
+    transform_aabb,
+};
+use crate::cascade_debug::{CascadeDebugMode, CascadeDebugSettings, cascade_selection_debug_color};
 use crate::copy_depth_prepass::PrepassTexture;
 use crate::draw_debug::DebugLines;
-use crate::prepare_lighting::GameLightingUniforms;
+use crate::fog_volume::FogVolumeUniforms;
+use crate::light_shadows::{LightShadowAssignments, LightShadowUniforms};
+use crate::post_process::{HdrTarget, PostProcessSettings};
+use crate::prepare_lighting::{
+    DEFAULT_MAX_POINT_LIGHTS, DrawLightUniforms, DynamicLights, GameLightingUniforms,
+};
 
-#[derive(UniformSet, Resource, Clone, Default)]
+#[derive(UniformSet, Resource, Clone)]
 #[uniform_set(prefix = "ub_")]
 pub struct Fog {
     pub fog_color: Vec4,
     pub caustics: Vec4,
+    /// World xz meters per second the caustic pattern slides with.
+    #[exclude]
+    pub caustics_scroll: Vec2,
+    /// How fast the caustic pattern changes shape.
+    #[exclude]
+    pub caustics_speed: f32,
+    /// xyz world meters per second the fog density noise drifts with, w how much of the density
+    /// it modulates (0 for uniform fog).
+    pub fog_noise: Vec4,
+    /// How far the caustic pattern has moved, set by `advance_fog_time`. The surface and fog
+    /// caustics both use it to stay in phase.
+    pub caustics_offset: Vec3,
+    /// How far the fog density noise has drifted, set by `advance_fog_time`.
+    pub fog_noise_offset: Vec3,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Fog {
+            fog_color: Vec4::ZERO,
+            caustics: Vec4::ZERO,
+            caustics_scroll: Vec2::ZERO,
+            caustics_speed: 0.1,
+            fog_noise: Vec4::ZERO,
+            caustics_offset: Vec3::ZERO,
+            fog_noise_offset: Vec3::ZERO,
+        }
+    }
+}
+
+/// `water_caustics` repeats when shifted by `m - (m.x + m.y + m.z) / 6` for any `m` whose
+/// components are multiples of this. Its simplex noise wraps at 289 and the second octave is
+/// scaled by 13 / 8.
+const CAUSTICS_PERIOD: f64 = 289.0 * 8.0;
+/// `value_noise` wraps at 256 and `fog_density_noise_at` samples it at 0.2 per meter.
+const FOG_NOISE_PERIOD: f64 = 256.0 / 0.2;
+
+/// Moves the caustics and fog noise by `Time<Virtual>`, so they stop while paused. Computed in
+/// f64 and wrapped at the patterns' periods, so they never jump or lose precision in the shader.
+pub fn advance_fog_time(time: Res<Time<Virtual>>, mut fog: ResMut<Fog>) {
+    let t = time.elapsed_secs_f64();
+    let scroll = fog.caustics_scroll.as_dvec2();
+    fog.caustics_offset =
+        wrap_caustics_offset(DVec3::new(scroll.x, fog.caustics_speed as f64, scroll.y) * t);
+    fog.fog_noise_offset = (fog.fog_noise.xyz().as_dvec3() * t)
+        .rem_euclid(DVec3::splat(FOG_NOISE_PERIOD))
+        .as_vec3();
+}
+
+fn wrap_caustics_offset(offset: DVec3) -> Vec3 {
+    // In the simplex noise's skewed space the period is the same along each axis.
+    let skewed = offset + DVec3::splat(offset.element_sum() / 3.0);
+    let skewed = skewed.rem_euclid(DVec3::splat(CAUSTICS_PERIOD));
+    (skewed - DVec3::splat(skewed.element_sum() / 6.0)).as_vec3()
 }
 
 pub fn standard_material_render(
@@ -43,6 +106,7 @@ pub fn standard_material_render(
         Has<SkipReflection>,
         Has<ReadReflection>,
         Option<&JointData>,
+        Option<&SelectedCascade>,
     )>,
     view_uniforms: Single<&ViewUniforms>,
     materials: Res<Assets<StandardMaterial>>,
@@ -52,18 +116,27 @@ pub fn standard_material_render(
     sorted: Res<DrawsSortedByMaterial>,
     mut enc: ResMut<CommandEncoder>,
     shadow: Option<Res<DirectionalLightShadow>>,
-    cascades: Query<&CascadeUniform>,
-    view_cascades: Query<&CascadeViewUniform>,
+    cascade_bvh: Res<CascadeBvh>,
     prepass: Option<ResMut<PrepassTexture>>,
     fog: Option<Res<Fog>>,
+    cascade_debug: Option<Res<CascadeDebugSettings>>,
+    dynamic_lights: Res<DynamicLights>,
+    // Grouped to stay within the system param limit.
+    (light_shadows, light_shadow_uniforms): (
+        Option<Res<LightShadowAssignments>>,
+        Option<Res<LightShadowUniforms>>,
+    ),
     mut debug: ResMut<DebugLines>,
 ) {
     let view_uniforms = view_uniforms.clone();
-    if cascades.iter().len() == 0 {
+    if cascade_bvh.uniforms.is_empty() {
         warn_once!("No cascades");
     }
 
     let phase = *phase;
+    let cascade_debug = cascade_debug
+        .is_some_and(|settings| settings.mode == CascadeDebugMode::MeshAssignment)
+        && !phase.depth_only();
 
     let iter = if phase.transparent() {
         Either::Right(mesh_entities.iter_many(transparent_draws.take()))
@@ -79,17 +152,30 @@ pub fn standard_material_render(
         read_reflect: bool,
         mesh: Handle<Mesh>,
         cascade_idx: u32,
+        blend_cascade_idx: u32,
+        cascade_blend: f32,
+        cascade_debug_color: Vec3,
+        light_set_idx: u32,
     }
 
     let mut draws = Vec::new();
     let mut render_materials: Vec<StandardMaterialUniforms> = Vec::new();
+    // Draws that pick the same lights share uniforms.
+    let mut light_sets: Vec<DrawLightUniforms> = Vec::new();
+    let mut light_set_indices: HashMap<Vec<u32>, u32> = HashMap::new();
+    let select_lights = !phase.depth_only() && !dynamic_lights.is_empty();
+    let light_shadow_uniforms = light_shadow_uniforms
+        .as_deref()
+        .filter(|_| select_lights && light_shadows.is_some_and(|shadows| !shadows.is_empty()))
+        .cloned();
 
     let v_pos = view_uniforms.view_position.to_vec3a();
-    let view_cascade_idx = select_cascade(
-        cascades,
-        obvhs::aabb::Aabb::new(v_pos - 0.01, v_pos + 0.01),
-        &mut debug,
-    );
+    let view_cascade_idx = cascade_bvh
+        .select(
+            obvhs::aabb::Aabb::new(v_pos - 0.01, v_pos + 0.01),
+            &mut debug,
+        )
+        .primary;
 
     let mut last_material = None;
     let mut current_material_idx = 0;
@@ -103,6 +189,7 @@ pub fn standard_material_render(
         skip_reflect,
         read_reflect,
         joint_data,
+        selected_cascade,
     ) in iter
     {
         if (phase.can_use_camera_frustum_cull() && !view_vis.get())
@@ -136,11 +223,29 @@ pub fn standard_material_render(
             render_materials.push(material.into());
         }
 
-        let draw_aabb = transform_aabb(
-            world_from_local,
-            obvhs::aabb::Aabb::new(aabb.min(), aabb.max()),
-        );
-        let cascade_idx = select_cascade(cascades, draw_aabb, &mut debug);
+        let draw_aabb = || {
+            transform_aabb(
+                world_from_local,
+                obvhs::aabb::Aabb::new(aabb.min(), aabb.max()),
+            )
+        };
+
+        let cascade_selection = match selected_cascade {
+            Some(selected) if selected.generation == cascade_bvh.generation => selected.selection,
+            _ => cascade_bvh.select(draw_aabb(), &mut debug),
+        };
+
+        let light_set_idx = if select_lights {
+            let selected = dynamic_lights.select(&draw_aabb(), DEFAULT_MAX_POINT_LIGHTS);
+            *light_set_indices
+                .entry(selected)
+                .or_insert_with_key(|selected| {
+                    light_sets.push(dynamic_lights.uniforms(selected));
+                    light_sets.len() as u32 - 1
+                })
+        } else {
+            0
+        };
 
         draws.push(Draw {
             // TODO don't copy full material
@@ -150,7 +255,11 @@ pub fn standard_material_render(
             material_h: material_h.id(),
             read_reflect,
             mesh: mesh.0.clone(),
-            cascade_idx,
+            cascade_idx: cascade_selection.primary,
+            blend_cascade_idx: cascade_selection.secondary,
+            cascade_blend: cascade_selection.blend,
+            cascade_debug_color: cascade_selection_debug_color(cascade_selection),
+            light_set_idx,
         });
     }
 
@@ -160,8 +269,9 @@ pub fn standard_material_render(
     let prepass = prepass.as_deref().cloned();
     let fog = fog.as_deref().cloned();
 
-    let cascades = cascades.iter().cloned().collect::<Vec<_>>();
-    let view_cascades = view_cascades.iter().cloned().collect::<Vec<_>>();
+    let cascades = cascade_bvh.uniforms.clone();
+    let view_cascades = cascade_bvh.view_uniforms.clone();
+    let blend_cascades = cascade_bvh.blend_uniforms.clone();
     enc.record(move |ctx, world| {
         let can_read_prepass = match phase {
             RenderPhase::ReflectOpaque
@@ -184,6 +294,23 @@ pub fn standard_material_render(
         };
 
         let lighting_uniforms = world.resource::<GameLightingUniforms>().clone();
+        // The reflection phases keep their own targets and encoding.
+        let hdr_target = world
+            .get_resource::<HdrTarget>()
+            .filter(|_| world.resource::<PostProcessSettings>().hdr)
+            .filter(|_| matches!(phase, RenderPhase::Opaque | RenderPhase::Transparent));
+        let hdr_output = hdr_target.is_some();
+        let hdr_float = hdr_target.is_some_and(|target| target.float);
+        if let Some(target) = hdr_target {
+            let clear_color =
+                matches!(phase, RenderPhase::Opaque).then(|| world.resource::<ClearColor>().0);
+            target.bind(ctx, clear_color);
+        }
+        let fog_volumes = if phase.depth_only() {
+            FogVolumeUniforms::default()
+        } else {
+            world.resource::<FogVolumeUniforms>().clone()
+        };
         #[allow(unexpected_cfgs)]
         let shader_index = shader_cached!(
             ctx,
@@ -210,23 +337,48 @@ pub fn standard_material_render(
                     ("THERES_CAUSTICS", "")
                 } else {
                     ("", "")
+                },
+                if cascade_debug {
+                    ("CASCADE_DEBUG", "")
+                } else {
+                    ("", "")
+                },
+                if light_shadow_uniforms.is_some() {
+                    ("LIGHT_SHADOWS", "")
+                } else {
+                    ("", "")
+                },
+                if hdr_output {
+                    ("HDR_OUTPUT", "")
+                } else {
+                    ("", "")
+                },
+                if hdr_float {
+                    ("HDR_FLOAT_TARGET", "")
+                } else {
+                    ("", "")
                 }
             ]
             .iter()
             .chain(
                 lighting_uniforms
-                    .shader_defs(true, shadow.is_some(), &phase)
+                    .shader_defs(select_lights, shadow.is_some(), &phase)
                     .iter()
             )
-            .chain(phase.shader_defs().iter()),
+            .chain(phase.shader_defs().iter())
+            .chain(fog_volumes.shader_defs().iter()),
             &[
                 ViewUniforms::bindings(),
                 StandardMaterialUniforms::bindings(),
                 GameLightingUniforms::bindings(),
+                DrawLightUniforms::bindings(),
+                LightShadowUniforms::bindings(),
                 CascadeUniform::bindings(),
                 CascadeViewUniform::bindings(),
+                CascadeBlendUniform::bindings(),
                 PrepassTexture::bindings(),
                 Fog::bindings(),
+                FogVolumeUniforms::bindings(),
             ]
         )
         .unwrap();
@@ -238,6 +390,7 @@ pub fn standard_material_render(
         ctx.map_uniform_set_locations::<StandardMaterialUniforms>();
         ctx.map_uniform_set_locations::<CascadeUniform>();
         ctx.map_uniform_set_locations::<CascadeViewUniform>();
+        ctx.map_uniform_set_locations::<CascadeBlendUniform>();
         ctx.map_uniform_set_locations::<PrepassTexture>();
         ctx.map_uniform_set_locations::<Fog>();
 
@@ -250,6 +403,11 @@ pub fn standard_material_render(
         if !phase.depth_only() {
             ctx.map_uniform_set_locations::<GameLightingUniforms>();
             ctx.bind_uniforms_set(world.resource::<GpuImages>(), &lighting_uniforms);
+            ctx.map_uniform_set_locations::<DrawLightUniforms>();
+            if let Some(light_shadow_uniforms) = &light_shadow_uniforms {
+                ctx.map_uniform_set_locations::<LightShadowUniforms>();
+                ctx.bind_uniforms_set(world.resource::<GpuImages>(), light_shadow_uniforms);
+            }
 
             reflect_bool_location = ctx.get_uniform_location("read_reflection");
             ctx.map_uniform_set_locations::<ReflectionUniforms>();
@@ -261,6 +419,10 @@ pub fn standard_material_render(
                 world.resource::<GpuImages>(),
                 fog.as_ref().unwrap_or(&Default::default()),
             );
+            if fog_volumes.fog_volume_count > 0 {
+                ctx.map_uniform_set_locations::<FogVolumeUniforms>();
+                ctx.bind_uniforms_set(world.resource::<GpuImages>(), &fog_volumes);
+            }
         }
 
         if can_read_prepass {
@@ -268,6 +430,7 @@ pub fn standard_material_render(
         }
 
         let mut last_material = None;
+        let mut last_light_set = None;
         for draw in &draws {
             let material = &render_materials[draw.material_idx as usize];
             set_blend_func_from_alpha_mode(&ctx.gl, &material.alpha_mode);
@@ -287,12 +450,29 @@ pub fn standard_material_render(
                 warn_once!("cascade {} not found", draw.cascade_idx);
             }
 
+            ctx.load("cascade_blend", draw.cascade_blend);
+            if cascade_debug {
+                ctx.load("cascade_debug_color", draw.cascade_debug_color);
+            }
+            if draw.cascade_blend > 0.0 {
+                if let Some(cascade) = blend_cascades.get(draw.blend_cascade_idx as usize) {
+                    ctx.bind_uniforms_set(images, cascade);
+                } else {
+                    warn_once!("cascade {} not found", draw.blend_cascade_idx);
+                }
+            }
+
             if let Some(cascade) = view_cascades.get(view_cascade_idx as usize) {
                 ctx.bind_uniforms_set(images, cascade);
             } else {
                 warn_once!("cascade {} not found", draw.cascade_idx);
             }
 
+            if select_lights && last_light_set != Some(draw.light_set_idx) {
+                ctx.bind_uniforms_set(images, &light_sets[draw.light_set_idx as usize]);
+                last_light_set = Some(draw.light_set_idx);
+            }
+
             if let Some(ref loc) = reflect_bool_location {
                 (draw.read_reflect && phase.read_reflect() && reflect_uniforms.is_some())
                     .load(&ctx.gl, loc)
@@ -328,3 +508,27 @@ pub fn generate_tangets(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caustics_offset_wraps_by_a_period() {
+        for offset in [
+            DVec3::new(0.15, 0.3, 0.05) * 3600.0,
+            DVec3::new(-5000.0, 12345.6, 0.5),
+            DVec3::new(1.0, 2.0, 3.0),
+        ] {
+            let wrapped = wrap_caustics_offset(offset).as_dvec3();
+            assert!(wrapped.abs().max_element() < CAUSTICS_PERIOD * 2.0);
+            // The difference in skewed space has to be a whole number of periods on every axis.
+            let diff = offset - wrapped;
+            let periods = (diff + DVec3::splat(diff.element_sum() / 3.0)) / CAUSTICS_PERIOD;
+            assert!(
+                (periods - periods.round()).abs().max_element() < 1e-5,
+                "{offset} wrapped to {wrapped}"
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Moves the sun along an authored day. A scene spawns a `TimeOfDay` with its `SceneContents`,
+//! and while it exists the sun's direction, color and illuminance, its `ShadowBounds`, the
+//! `ClearColor` and the `Fog` follow the curve. When it's removed they are put back to what they
+//! were before, so the next scene doesn't inherit the hour the last one was left at.
+
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bgl2::phase_shadow::ShadowBounds;
+
+use crate::std_mat_render::Fog;
+
+#[derive(Resource, Default)]
+pub struct TimeOfDayPlugin;
+
+impl Plugin for TimeOfDayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, advance_time_of_day)
+            .add_observer(save_sun_before_time_of_day)
+            .add_observer(restore_sun_before_time_of_day);
+    }
+}
+
+#[derive(Component, Clone)]
+pub struct TimeOfDay {
+    /// Wraps at 24.
+    pub hour: f32,
+    /// In game hours per real second.
+    pub speed: f32,
+    pub curve: SunCurve,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: f32, speed: f32, curve: SunCurve) -> Self {
+        TimeOfDay { hour, speed, curve }
+    }
+}
+
+/// Keys sorted by hour, interpolated linearly and wrapping from the last key to the first
+/// across midnight.
+#[derive(Clone)]
+pub struct SunCurve(pub Vec<SunKeyframe>);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SunKeyframe {
+    pub hour: f32,
+    /// Radians above the horizon.
+    pub elevation: f32,
+    /// Radians from -Z towards +X.
+    pub azimuth: f32,
+    pub color: Color,
+    pub illuminance: f32,
+    /// Half extent of the sun's `ShadowBounds`, low sun needs more for its long shadows.
+    pub shadow_extent: f32,
+    pub clear_color: Color,
+    pub fog_color: Vec4,
+}
+
+impl SunCurve {
+    pub fn sample(&self, hour: f32) -> Option<SunKeyframe> {
+        let keys = &self.0;
+        let (first, last) = (keys.first()?, keys.last()?);
+        let hour = hour.rem_euclid(24.0);
+        let next = keys.iter().position(|k| k.hour > hour).unwrap_or(0);
+        let (prev, next) = if next == 0 {
+            (last, first)
+        } else {
+            (&keys[next - 1], &keys[next])
+        };
+        let span = (next.hour - prev.hour).rem_euclid(24.0);
+        let f = if span > 0.0 {
+            ((hour - prev.hour).rem_euclid(24.0) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let lerp = |a: f32, b: f32| a + (b - a) * f;
+        let lerp_color = |a: Color, b: Color| {
+            Color::from(LinearRgba::from_vec3(
+                a.to_linear().to_vec3().lerp(b.to_linear().to_vec3(), f),
+            ))
+        };
+        // Shortest way around.
+        let azimuth_delta = (next.azimuth - prev.azimuth + TAU * 0.5).rem_euclid(TAU) - TAU * 0.5;
+        Some(SunKeyframe {
+            hour,
+            elevation: lerp(prev.elevation, next.elevation),
+            azimuth: prev.azimuth + azimuth_delta * f,
+            color: lerp_color(prev.color, next.color),
+            illuminance: lerp(prev.illuminance, next.illuminance),
+            shadow_extent: lerp(prev.shadow_extent, next.shadow_extent),
+            clear_color: lerp_color(prev.clear_color, next.clear_color),
+            fog_color: prev.fog_color.lerp(next.fog_color, f),
+        })
+    }
+}
+
+impl SunKeyframe {
+    /// Unit vector pointing from the ground towards the sun.
+    pub fn to_sun(&self) -> Vec3 {
+        let (sin_el, cos_el) = self.elevation.sin_cos();
+        let (sin_az, cos_az) = self.azimuth.sin_cos();
+        vec3(sin_az * cos_el, sin_el, -cos_az * cos_el)
+    }
+}
+
+/// What `advance_time_of_day` overwrites, as it was when the `TimeOfDay` was added.
+#[derive(Resource)]
+struct SunBeforeTimeOfDay {
+    rotation: Quat,
+    color: Color,
+    illuminance: f32,
+    shadows_enabled: bool,
+    /// Moved out and replaced by the curve's, None if the curve is empty.
+    shadow_bounds: Option<ShadowBounds>,
+    clear_color: Color,
+    fog_color: Vec4,
+}
+
+fn save_sun_before_time_of_day(
+    add: On<Add, TimeOfDay>,
+    mut commands: Commands,
+    time_of_day: Query<&TimeOfDay>,
+    sun: Single<(&Transform, &DirectionalLight, &mut ShadowBounds)>,
+    clear: Res<ClearColor>,
+    fog: Res<Fog>,
+    saved: Option<Res<SunBeforeTimeOfDay>>,
+) {
+    // Keep the values from before the first one if a TimeOfDay replaces another.
+    if saved.is_some() {
+        return;
+    }
+    let (trans, light, mut shadow_bounds) = sun.into_inner();
+    let key = time_of_day
+        .get(add.entity)
+        .ok()
+        .and_then(|time_of_day| time_of_day.curve.sample(time_of_day.hour));
+    commands.insert_resource(SunBeforeTimeOfDay {
+        rotation: trans.rotation,
+        color: light.color,
+        illuminance: light.illuminance,
+        shadows_enabled: light.shadows_enabled,
+        shadow_bounds: key.map(|key| {
+            std::mem::replace(&mut *shadow_bounds, ShadowBounds::cube(key.shadow_extent))
+        }),
+        clear_color: clear.0,
+        fog_color: fog.fog_color,
+    });
+}
+
+fn restore_sun_before_time_of_day(
+    _remove: On<Remove, TimeOfDay>,
+    mut commands: Commands,
+    saved: Option<ResMut<SunBeforeTimeOfDay>>,
+    sun: Single<(&mut Transform, &mut DirectionalLight, &mut ShadowBounds)>,
+    mut clear: ResMut<ClearColor>,
+    mut fog: ResMut<Fog>,
+) {
+    let Some(mut saved) = saved else {
+        return;
+    };
+    let (mut trans, mut light, mut shadow_bounds) = sun.into_inner();
+    trans.rotation = saved.rotation;
+    light.color = saved.color;
+    light.illuminance = saved.illuminance;
+    light.shadows_enabled = saved.shadows_enabled;
+    if let Some(bounds) = saved.shadow_bounds.take() {
+        *shadow_bounds = bounds;
+    }
+    clear.0 = saved.clear_color;
+    fog.fog_color = saved.fog_color;
+    commands.remove_resource::<SunBeforeTimeOfDay>();
+}
+
+fn advance_time_of_day(
+    time: Res<Time>,
+    time_of_day: Option<Single<&mut TimeOfDay>>,
+    sun: Single<(&mut Transform, &mut DirectionalLight, &mut ShadowBounds)>,
+    mut clear: ResMut<ClearColor>,
+    mut fog: ResMut<Fog>,
+) {
+    let Some(mut time_of_day) = time_of_day else {
+        return;
+    };
+    time_of_day.hour = (time_of_day.hour + time_of_day.speed * time.delta_secs()).rem_euclid(24.0);
+    let Some(key) = time_of_day.curve.sample(time_of_day.hour) else {
+        return;
+    };
+
+    let (mut trans, mut light, mut shadow_bounds) = sun.into_inner();
+    let to_sun = key.to_sun();
+    let up = if to_sun.y.abs() < 0.99 {
+        Vec3::Y
+    } else {
+        Vec3::Z
+    };
+    trans.rotation = Transform::IDENTITY.looking_to(-to_sun, up).rotation;
+    light.color = key.color;
+    // Below the horizon the sun is off, the curve only carries its color through the night.
+    let above_horizon = key.elevation > 0.0;
+    light.illuminance = if above_horizon { key.illuminance } else { 0.0 };
+    light.shadows_enabled = above_horizon;
+    *shadow_bounds = ShadowBounds::cube(key.shadow_extent);
+    clear.0 = key.clear_color;
+    fog.fog_color = key.fog_color;
+}